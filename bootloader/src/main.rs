@@ -0,0 +1,129 @@
+#![no_main]
+#![no_std]
+
+// The resident bootloader: picks one of the two application slots and boots it.
+// It never writes to the flash, so it can't be broken by an update.
+
+use core::{panic::PanicInfo, ptr};
+use cortex_m_rt::entry;
+
+#[allow(dead_code)]
+#[path = "../../src/slots.rs"]
+mod slots;
+
+use slots::{SlotId, Trial};
+
+// The location of the ST bootloader in system memory, used when neither slot holds
+// a usable image. See Application Note AN2606.
+const SYSTEM_BOOTLOADER_LOCATION: u32 = 0x1FFF_0000;
+
+// What it takes to write the backup registers, without pulling in the PAC.
+const RCC_APB1ENR: *mut u32 = 0x4002_3840 as *mut u32;
+const RCC_BDCR: *mut u32 = 0x4002_3870 as *mut u32;
+const PWR_CR: *mut u32 = 0x4000_7000 as *mut u32;
+const RTC_BKP0R: *mut u32 = 0x4000_2850 as *mut u32;
+const APB1ENR_PWREN: u32 = 1 << 28;
+const BDCR_RTCEN: u32 = 1 << 15;
+const PWR_CR_DBP: u32 = 1 << 8;
+
+// The independent watchdog, see RM0383 section 15. Runs off the ~32kHz LSI.
+const IWDG_KR: *mut u32 = 0x4000_3000 as *mut u32;
+const IWDG_PR: *mut u32 = 0x4000_3004 as *mut u32;
+const IWDG_RLR: *mut u32 = 0x4000_3008 as *mut u32;
+const IWDG_SR: *mut u32 = 0x4000_300C as *mut u32;
+const IWDG_KEY_START: u32 = 0xCCCC;
+const IWDG_KEY_UNLOCK: u32 = 0x5555;
+const IWDG_KEY_FEED: u32 = 0xAAAA;
+// Divides the LSI down to ~1kHz.
+const IWDG_PRESCALER_32: u32 = 3;
+// Plenty for the firmware to get to its own watchdog setup, which takes over.
+const TRIAL_WATCHDOG_TIMEOUT_MS: u32 = 2000;
+
+#[entry]
+fn main() -> ! {
+    let active = slots::read_active_slot().unwrap_or(SlotId::A);
+
+    let (slot, on_trial) = match Trial::from_bits(read_backup_register(slots::TRIAL_REGISTER)) {
+        // Give the new firmware its one chance.
+        Trial::Pending(slot) => {
+            write_backup_register(slots::TRIAL_REGISTER, Trial::Booted(slot).to_bits());
+            (slot, true)
+        },
+        // It was booted and never confirmed before this reset, so go back.
+        Trial::Booted(slot) if slot != active => {
+            write_backup_register(slots::TRIAL_REGISTER, Trial::RolledBack(slot).to_bits());
+            (active, false)
+        },
+        _ => (active, false),
+    };
+
+    if slots::image_is_valid(slot) {
+        if on_trial {
+            // If it hangs before it even starts the watchdog, the reset still rolls it back.
+            start_watchdog(TRIAL_WATCHDOG_TIMEOUT_MS);
+        }
+        boot(slot.start());
+    } else if slots::image_is_valid(slot.other()) {
+        boot(slot.other().start());
+    } else {
+        // Nothing to run, wait for a new firmware over USB DFU instead.
+        boot(SYSTEM_BOOTLOADER_LOCATION);
+    }
+}
+
+fn boot(vector_table: u32) -> ! {
+    // Safety: The vector table is a valid image, checked by the caller, or the ST bootloader.
+    // Nothing has been set up that the image doesn't expect after a reset.
+    unsafe {
+        (*cortex_m::peripheral::SCB::ptr()).vtor.write(vector_table);
+        cortex_m::asm::bootload(vector_table as *const u32)
+    }
+}
+
+fn start_watchdog(timeout_ms: u32) {
+    // Safety: Nothing else uses the watchdog before the firmware starts, which reconfigures
+    // it the same way. Once started, it can't be stopped short of a reset.
+    unsafe {
+        ptr::write_volatile(IWDG_KR, IWDG_KEY_START);
+        ptr::write_volatile(IWDG_KR, IWDG_KEY_UNLOCK);
+        ptr::write_volatile(IWDG_PR, IWDG_PRESCALER_32);
+        ptr::write_volatile(IWDG_RLR, timeout_ms.min(0xFFF));
+        // The new values only take effect once the LSI domain has picked them up.
+        while ptr::read_volatile(IWDG_SR) != 0 {}
+        ptr::write_volatile(IWDG_KR, IWDG_KEY_FEED);
+    }
+}
+
+fn read_backup_register(index: usize) -> u32 {
+    // Safety: Reading the backup registers has no side effects.
+    unsafe { ptr::read_volatile(RTC_BKP0R.add(index)) }
+}
+
+fn write_backup_register(index: usize, value: u32) {
+    // Safety: Enables write access to the backup domain the same way the firmware does,
+    // see `BackupDomain::with_write_access()`, then puts the access bits back.
+    unsafe {
+        let apb1enr = ptr::read_volatile(RCC_APB1ENR);
+        let bdcr = ptr::read_volatile(RCC_BDCR);
+
+        ptr::write_volatile(RCC_APB1ENR, apb1enr | APB1ENR_PWREN);
+        // Stall the pipeline to work around erratum 2.1.13 (DM00037591).
+        cortex_m::asm::dsb();
+
+        // Only readable now that its clock is enabled.
+        let pwr_cr = ptr::read_volatile(PWR_CR);
+        ptr::write_volatile(PWR_CR, pwr_cr | PWR_CR_DBP);
+        ptr::write_volatile(RCC_BDCR, bdcr | BDCR_RTCEN);
+
+        ptr::write_volatile(RTC_BKP0R.add(index), value);
+
+        ptr::write_volatile(RCC_BDCR, bdcr);
+        ptr::write_volatile(PWR_CR, pwr_cr);
+        ptr::write_volatile(RCC_APB1ENR, apb1enr);
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
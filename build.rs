@@ -1,5 +1,73 @@
+use std::{env, fs, path::PathBuf};
+
+#[allow(dead_code)]
+#[path = "src/slots.rs"]
+mod slots;
+
 fn main() {
     println!("cargo:rustc-env=PANEL_SERIAL_NUMBER={}", get_git_commit_short(),);
+    // Once anything is listed, changes to the rest of the package no longer rerun this.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=src/slots.rs");
+
+    write_slot_memory();
+    write_update_key();
+}
+
+/// Writes slot.x, included by memory.x, with the FLASH region of the slot the firmware
+/// is being built for.
+fn write_slot_memory() {
+    let slot = if env::var_os("CARGO_FEATURE_SLOT_B").is_some() {
+        slots::SlotId::B
+    } else {
+        slots::SlotId::A
+    };
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    let memory = format!(
+        "MEMORY\n{{\n  FLASH : ORIGIN = 0x{:08X}, LENGTH = {}K\n}}\n",
+        slot.start(),
+        slots::SLOT_SIZE / 1024
+    );
+
+    fs::write(out_dir.join("slot.x"), memory).expect("Couldn't write slot.x");
+    println!("cargo:rustc-link-search={}", out_dir.display());
+}
+
+/// Writes update_key.rs, with the public key firmware updates have to be signed with.
+/// Without a release key the firmware is built with no key, and rejects every update.
+fn write_update_key() {
+    println!("cargo:rerun-if-env-changed=PANEL_RELEASE_KEY");
+    println!("cargo:rerun-if-changed=keys/dev.pub");
+
+    let key_path = if env::var_os("CARGO_FEATURE_DEV_KEY").is_some() {
+        Some(PathBuf::from("keys/dev.pub"))
+    } else {
+        env::var_os("PANEL_RELEASE_KEY").map(PathBuf::from)
+    };
+
+    let key = match key_path {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.display());
+            let key = fs::read(&path)
+                .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e));
+            if key.len() != 32 {
+                panic!("{} isn't a raw 32 byte Ed25519 public key", path.display());
+            }
+
+            format!("Some({:?})", key)
+        },
+        None => {
+            println!(
+                "cargo:warning=PANEL_RELEASE_KEY is not set, this firmware will reject all updates"
+            );
+            "None".to_string()
+        },
+    };
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    fs::write(out_dir.join("update_key.rs"), key).expect("Couldn't write update_key.rs");
 }
 
 fn get_git_commit() -> String {
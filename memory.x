@@ -1,10 +1,19 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* The flash is split between the resident bootloader and two application slots:
+       0x08000000   32K  resident bootloader, see bootloader/memory.x
+       0x08008000   16K  boot state
+       0x08010000  192K  slot A
+       0x08040000  192K  slot B
+     See src/slots.rs for the details. */
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
+/* The FLASH region of the slot being built for (slot A, or slot B with the `slot-b`
+   feature), generated by build.rs. */
+INCLUDE slot.x
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
@@ -0,0 +1,252 @@
+use hal::stm32::{self, PWR, RTC};
+use stm32f4xx_hal as hal;
+
+use crate::{
+    reset_cause::BootReason,
+    slots::{self, Trial},
+};
+use core::marker::PhantomData;
+
+// The STM32F411 has 20 backup registers, each of which is 32-bits wide.
+// They keep their contents across resets, as long as power is applied.
+pub const BACKUP_REGISTER_COUNT: usize = 20;
+
+// Every backup register in use is allocated here, so they can't accidentally overlap.
+//   0:     Magic number requesting the ST bootloader, see `bootload`.
+//   1:     The reason for the next boot, see `reset_cause`.
+//   2:     The trial of a freshly installed firmware, shared with the resident bootloader.
+//   3-19:  The record of the last panic, see `crash`.
+pub const BOOTLOADER_MAGIC: Slot<u32> = Slot::new(0);
+pub const BOOT_REASON: Slot<BootReason> = Slot::new(1);
+pub const BOOT_TRIAL: Slot<Trial> = Slot::new(slots::TRIAL_REGISTER);
+pub const CRASH_HEADER: Slot<u32> = Slot::new(3);
+pub const CRASH_FILE: SlotBlock = SlotBlock::new(4, 5);
+pub const CRASH_MESSAGE: SlotBlock = SlotBlock::new(9, 11);
+
+/// A value which can be stored in a single backup register.
+pub trait BackupValue: Sized {
+    fn to_bits(&self) -> u32;
+
+    fn from_bits(bits: u32) -> Self;
+}
+
+impl BackupValue for u32 {
+    fn to_bits(&self) -> u32 {
+        *self
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        bits
+    }
+}
+
+impl BackupValue for Trial {
+    fn to_bits(&self) -> u32 {
+        Trial::to_bits(self)
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        Trial::from_bits(bits)
+    }
+}
+
+/// A single backup register holding a `T`.
+pub struct Slot<T: BackupValue> {
+    index: usize,
+    _value: PhantomData<T>,
+}
+
+impl<T: BackupValue> Slot<T> {
+    const fn new(index: usize) -> Self {
+        assert!(index < BACKUP_REGISTER_COUNT);
+        Self { index, _value: PhantomData }
+    }
+}
+
+/// Consecutive backup registers holding bytes, 4 per register.
+pub struct SlotBlock {
+    start: usize,
+    len: usize,
+}
+
+impl SlotBlock {
+    const fn new(start: usize, len: usize) -> Self {
+        assert!(start + len <= BACKUP_REGISTER_COUNT);
+        Self { start, len }
+    }
+
+    pub const fn byte_len(&self) -> usize {
+        self.len * 4
+    }
+}
+
+/// The single owner of the backup registers.
+pub struct BackupDomain {
+    rtc: RTC,
+    pwr: PWR,
+}
+
+impl BackupDomain {
+    pub fn new(rtc: RTC, pwr: PWR) -> Self {
+        Self { rtc, pwr }
+    }
+
+    /// For use where the owner can't be reached, such as in the panic handler.
+    ///
+    /// # Safety
+    /// Nothing else may access the backup domain while the returned value is in use.
+    pub unsafe fn steal() -> Self {
+        let dp = stm32::Peripherals::steal();
+        Self { rtc: dp.RTC, pwr: dp.PWR }
+    }
+
+    pub fn read<T: BackupValue>(&self, slot: &Slot<T>) -> T {
+        T::from_bits(self.read_register(slot.index))
+    }
+
+    pub fn write<T: BackupValue>(&mut self, slot: &Slot<T>, value: T) {
+        self.with_write_access(|rtc| {
+            rtc.bkpr[slot.index].write(|w| w.bkp().bits(value.to_bits()));
+        });
+    }
+
+    /// Fills `bytes` from the block, up to the length of the shorter of the two.
+    pub fn read_block(&self, block: &SlotBlock, bytes: &mut [u8]) {
+        let words = (0..block.len).map(|i| self.read_register(block.start + i));
+        unpack_block(words, bytes);
+    }
+
+    /// Writes `bytes` to the block, zero padding the rest of it.
+    pub fn write_block(&mut self, block: &SlotBlock, bytes: &[u8]) {
+        self.with_write_access(|rtc| {
+            for i in 0..block.len {
+                rtc.bkpr[block.start + i].write(|w| w.bkp().bits(block_word(bytes, i)));
+            }
+        });
+    }
+
+    fn read_register(&self, index: usize) -> u32 {
+        self.rtc.bkpr[index].read().bkp().bits()
+    }
+
+    /// Enables write access to the backup domain around `f`, then puts the access bits
+    /// back the way they were. Only the bits involved are touched.
+    fn with_write_access<R>(&mut self, f: impl FnOnce(&RTC) -> R) -> R {
+        // Safety: Only the PWREN and RTCEN bits are modified, with read-modify-write.
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+
+        let pwren = rcc.apb1enr.read().pwren().bit();
+        let dbp = self.pwr.cr.read().dbp().bit();
+        let rtcen = rcc.bdcr.read().rtcen().bit();
+
+        // Enable the power interface clock by setting the PWREN bits in the RCC_APB1ENR register.
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+
+        // Stall the pipeline to work around erratum 2.1.13 (DM00037591).
+        cortex_m::asm::dsb();
+
+        // Set the DBP bit in the Section 5.4.1 to enable access to the backup domain.
+        self.pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        // Enable the RTC clock by programming the RTCEN [15] bit in the Section 7.3.20: RCC Backup domain control register (RCC_BDCR).
+        rcc.bdcr.modify(|_, w| w.rtcen().set_bit());
+
+        let result = f(&self.rtc);
+
+        rcc.bdcr.modify(|_, w| w.rtcen().bit(rtcen));
+        self.pwr.cr.modify(|_, w| w.dbp().bit(dbp));
+        rcc.apb1enr.modify(|_, w| w.pwren().bit(pwren));
+
+        result
+    }
+}
+
+/// The `i`th register of a block holding `bytes`, zero padded.
+fn block_word(bytes: &[u8], i: usize) -> u32 {
+    let mut word = [0u8; 4];
+    if let Some(chunk) = bytes.chunks(4).nth(i) {
+        word[..chunk.len()].copy_from_slice(chunk);
+    }
+
+    u32::from_le_bytes(word)
+}
+
+/// Fills `bytes` from the registers of a block, up to the length of the shorter of the two.
+fn unpack_block(words: impl Iterator<Item = u32>, bytes: &mut [u8]) {
+    for (chunk, word) in bytes.chunks_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::SlotId;
+
+    fn round_trip<T: BackupValue>(value: T) -> T {
+        T::from_bits(value.to_bits())
+    }
+
+    #[test]
+    fn slots_do_not_overlap() {
+        // The first register and the number of registers of every allocation.
+        let mut allocations = [
+            (BOOTLOADER_MAGIC.index, 1),
+            (BOOT_REASON.index, 1),
+            (BOOT_TRIAL.index, 1),
+            (CRASH_HEADER.index, 1),
+            (CRASH_FILE.start, CRASH_FILE.len),
+            (CRASH_MESSAGE.start, CRASH_MESSAGE.len),
+        ];
+        allocations.sort();
+
+        for pair in allocations.windows(2) {
+            let ((start, len), (next_start, _)) = (pair[0], pair[1]);
+            assert!(start + len <= next_start, "{:?} overlaps {:?}", pair[0], pair[1]);
+        }
+
+        let (last_start, last_len) = allocations[allocations.len() - 1];
+        assert!(last_start + last_len <= BACKUP_REGISTER_COUNT);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        assert_eq!(round_trip(0xDEAD_BEEFu32), 0xDEAD_BEEF);
+
+        for &reason in &[BootReason::Unknown, BootReason::Bootloader, BootReason::Panic] {
+            assert_eq!(round_trip(reason), reason);
+        }
+
+        for &slot in &[SlotId::A, SlotId::B] {
+            for &trial in &[Trial::Pending(slot), Trial::Booted(slot), Trial::RolledBack(slot)] {
+                assert_eq!(round_trip(trial), trial);
+            }
+        }
+        assert_eq!(round_trip(Trial::None), Trial::None);
+    }
+
+    #[test]
+    fn cleared_registers_read_as_nothing() {
+        assert_eq!(BootReason::from_bits(0), BootReason::Unknown);
+        assert_eq!(Trial::from_bits(0), Trial::None);
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let text = b"src/main.rs";
+        let words: [u32; 5] = [0, 1, 2, 3, 4].map(|i| block_word(text, i));
+        assert_eq!(words[0], u32::from_le_bytes(*b"src/"));
+        assert_eq!(words[2], u32::from_le_bytes([b'.', b'r', b's', 0]));
+        assert_eq!(words[3..], [0, 0]);
+
+        let mut bytes = [0xFFu8; 20];
+        unpack_block(words.iter().copied(), &mut bytes);
+        assert_eq!(bytes[..text.len()], text[..]);
+        assert!(bytes[text.len()..].iter().all(|&byte| byte == 0));
+
+        // Reading into a shorter buffer stops at its end.
+        let mut bytes = [0u8; 6];
+        unpack_block(words.iter().copied(), &mut bytes);
+        assert_eq!(bytes, *b"src/ma");
+    }
+}
@@ -1,3 +1,4 @@
+use crate::clock::{Instant, TICK_PERIOD_MS};
 use core::convert::Infallible;
 use embedded_hal::digital::v2::InputPin;
 
@@ -19,6 +20,157 @@ enum ButtonState {
     Pressed,
 }
 
+/// Higher level button events, recognized from the timing of presses and releases.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    TripleClick,
+
+    /// The button has been held down for the long press time.
+    LongPressStart,
+
+    /// Sent periodically while a long press continues.
+    LongPressRepeat,
+
+    /// The button was released after a long press.
+    LongPressEnd,
+}
+
+impl Gesture {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Gesture::Click => 0,
+            Gesture::DoubleClick => 1,
+            Gesture::TripleClick => 2,
+            Gesture::LongPressStart => 3,
+            Gesture::LongPressRepeat => 4,
+            Gesture::LongPressEnd => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GestureTiming {
+    /// How long to wait after a release for another click.
+    pub multi_click_ms: u16,
+
+    /// How long the button must be held down to start a long press.
+    pub long_press_ms: u16,
+
+    /// The interval of `LongPressRepeat` events, 0 disables them.
+    pub long_press_repeat_ms: u16,
+}
+
+impl Default for GestureTiming {
+    fn default() -> Self {
+        Self { multi_click_ms: 300, long_press_ms: 600, long_press_repeat_ms: 200 }
+    }
+}
+
+enum GestureState {
+    Idle,
+    Pressed {
+        since: Instant,
+        clicks: u8,
+    },
+    WaitingForClick {
+        released_at: Instant,
+        clicks: u8,
+    },
+    LongPress {
+        last_repeat: Instant,
+    },
+    /// The press turned out to be something else, ignore it until release.
+    Cancelled,
+}
+
+pub struct GestureRecognizer {
+    state: GestureState,
+    timing: GestureTiming,
+    /// The clicks completed before a cancelled press, reported on the next update.
+    cancelled_clicks: Option<Gesture>,
+}
+
+impl GestureRecognizer {
+    pub fn new(timing: GestureTiming) -> Self {
+        Self { state: GestureState::Idle, timing, cancelled_clicks: None }
+    }
+
+    pub fn set_timing(&mut self, timing: GestureTiming) {
+        self.timing = timing;
+    }
+
+    /// Stops the current press from being recognized as a gesture, for example
+    /// because the dial was turned while it was held down. Long presses which
+    /// have already started still end with `LongPressEnd`, and the clicks before
+    /// the press are still reported as a `Click` or `DoubleClick` on the next update.
+    pub fn cancel(&mut self) {
+        if let GestureState::Pressed { clicks, .. } = self.state {
+            self.cancelled_clicks = match clicks {
+                0 => None,
+                1 => Some(Gesture::Click),
+                _ => Some(Gesture::DoubleClick),
+            };
+            self.state = GestureState::Cancelled;
+        }
+    }
+
+    /// Feeds the latest button event into the recognizer. Should be called on every
+    /// iteration, even without an event, so that time based gestures are recognized.
+    pub fn update(&mut self, event: Option<&ButtonEvent>, now: Instant) -> Option<Gesture> {
+        // A cancelled press doesn't make any gestures of its own.
+        if let Some(gesture) = self.cancelled_clicks.take() {
+            self.update_state(event, now);
+            return Some(gesture);
+        }
+
+        self.update_state(event, now)
+    }
+
+    fn update_state(&mut self, event: Option<&ButtonEvent>, now: Instant) -> Option<Gesture> {
+        let (state, gesture) = match (&self.state, event) {
+            (GestureState::Idle, Some(ButtonEvent::Press)) => {
+                (GestureState::Pressed { since: now, clicks: 0 }, None)
+            },
+            (GestureState::Pressed { clicks, .. }, Some(ButtonEvent::Release)) => {
+                match clicks + 1 {
+                    3 => (GestureState::Idle, Some(Gesture::TripleClick)),
+                    clicks => (GestureState::WaitingForClick { released_at: now, clicks }, None),
+                }
+            },
+            (GestureState::Pressed { since, .. }, None)
+                if now.has_elapsed(*since, self.timing.long_press_ms as u32) =>
+            {
+                (GestureState::LongPress { last_repeat: now }, Some(Gesture::LongPressStart))
+            },
+            (GestureState::WaitingForClick { clicks, .. }, Some(ButtonEvent::Press)) => {
+                (GestureState::Pressed { since: now, clicks: *clicks }, None)
+            },
+            (GestureState::WaitingForClick { released_at, clicks }, None)
+                if now.has_elapsed(*released_at, self.timing.multi_click_ms as u32) =>
+            {
+                let gesture = if *clicks == 1 { Gesture::Click } else { Gesture::DoubleClick };
+                (GestureState::Idle, Some(gesture))
+            },
+            (GestureState::LongPress { .. }, Some(ButtonEvent::Release)) => {
+                (GestureState::Idle, Some(Gesture::LongPressEnd))
+            },
+            (GestureState::LongPress { last_repeat }, None)
+                if self.timing.long_press_repeat_ms > 0
+                    && now.has_elapsed(*last_repeat, self.timing.long_press_repeat_ms as u32) =>
+            {
+                (GestureState::LongPress { last_repeat: now }, Some(Gesture::LongPressRepeat))
+            },
+            (GestureState::Cancelled, Some(ButtonEvent::Release)) => (GestureState::Idle, None),
+            _ => return None,
+        };
+
+        self.state = state;
+        gesture
+    }
+}
+
 impl<T: InputPin<Error = Infallible>> Button<T> {
     pub fn new(pin: Debouncer<T>) -> Self {
         let button_state = ButtonState::Released;
@@ -30,8 +182,12 @@ impl<T: InputPin<Error = Infallible>> Button<T> {
         self.pin.is_pressed()
     }
 
-    pub fn poll(&mut self) -> Option<ButtonEvent> {
-        self.pin.poll();
+    pub fn set_debounce_time_ms(&mut self, debounce_time_ms: u16) {
+        self.pin.set_debounce_time_ms(debounce_time_ms);
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<ButtonEvent> {
+        self.pin.poll(now);
 
         match self.button_state {
             ButtonState::Released => {
@@ -54,10 +210,16 @@ impl<T: InputPin<Error = Infallible>> Button<T> {
 
 // Debouncer code inspired by Kenneth Kuhn's C debouncer:
 // http://www.kennethkuhn.com/electronics/debounce.c
+//
+// Instead of counting samples, the integrator counts milliseconds, so the debounce
+// time doesn't depend on how often `poll()` gets called. A sample never counts for more
+// than the main loop sleeps between polls, so a single one can't settle the output after
+// the loop has been held up, for example by a flash erase.
 pub struct Debouncer<T: InputPin> {
     pin: T,
-    integrator: u8,
-    max: u8,
+    integrator: u32,
+    max: u32,
+    last_poll: Instant,
     output: bool,
     active_mode: Active,
 }
@@ -69,8 +231,8 @@ pub enum Active {
 }
 
 impl<T: InputPin<Error = Infallible>> Debouncer<T> {
-    pub fn new(pin: T, active_mode: Active, debounce_time_ms: u16, sample_frequency: u16) -> Self {
-        let max = ((debounce_time_ms as f32 / 1000.0) * sample_frequency as f32) as u8;
+    pub fn new(pin: T, active_mode: Active, debounce_time_ms: u16, now: Instant) -> Self {
+        let max = Self::max_for(debounce_time_ms);
 
         let integrator = match active_mode {
             Active::Low => max,
@@ -82,14 +244,27 @@ impl<T: InputPin<Error = Infallible>> Debouncer<T> {
             Active::High => false,
         };
 
-        Self { pin, integrator, max, output, active_mode }
+        Self { pin, integrator, max, last_poll: now, output, active_mode }
+    }
+
+    pub fn set_debounce_time_ms(&mut self, debounce_time_ms: u16) {
+        self.max = Self::max_for(debounce_time_ms);
+        self.integrator = self.integrator.min(self.max);
+    }
+
+    fn max_for(debounce_time_ms: u16) -> u32 {
+        // The integrator needs at least a millisecond of headroom to tell the states apart.
+        (debounce_time_ms as u32).max(1)
     }
 
-    pub fn poll(&mut self) {
+    pub fn poll(&mut self, now: Instant) {
+        let elapsed = now.ms_since(self.last_poll).min(TICK_PERIOD_MS);
+        self.last_poll = now;
+
         if self.pin.is_low().unwrap() {
-            self.integrator = self.integrator.saturating_sub(1);
-        } else if self.integrator < self.max {
-            self.integrator += 1;
+            self.integrator = self.integrator.saturating_sub(elapsed);
+        } else {
+            self.integrator = self.integrator.saturating_add(elapsed).min(self.max);
         }
 
         if self.integrator == 0 {
@@ -103,3 +278,168 @@ impl<T: InputPin<Error = Infallible>> Debouncer<T> {
         matches!((&self.active_mode, self.output), (Active::High, true) | (Active::Low, false))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING: GestureTiming =
+        GestureTiming { multi_click_ms: 300, long_press_ms: 600, long_press_repeat_ms: 200 };
+
+    /// Feeds `events`, each at its time in milliseconds, and collects the gestures.
+    fn gestures(
+        recognizer: &mut GestureRecognizer,
+        events: &[(u32, Option<ButtonEvent>)],
+    ) -> Vec<Gesture> {
+        events
+            .iter()
+            .filter_map(|(ms, event)| recognizer.update(event.as_ref(), Instant::from_ms(*ms)))
+            .collect()
+    }
+
+    fn press(ms: u32) -> (u32, Option<ButtonEvent>) {
+        (ms, Some(ButtonEvent::Press))
+    }
+
+    fn release(ms: u32) -> (u32, Option<ButtonEvent>) {
+        (ms, Some(ButtonEvent::Release))
+    }
+
+    fn idle(ms: u32) -> (u32, Option<ButtonEvent>) {
+        (ms, None)
+    }
+
+    #[test]
+    fn clicks() {
+        let mut recognizer = GestureRecognizer::new(TIMING);
+
+        let events = [press(0), release(100), idle(399), idle(400)];
+        assert_eq!(gestures(&mut recognizer, &events), [Gesture::Click]);
+
+        let events = [press(1000), release(1100), press(1300), release(1400), idle(1700)];
+        assert_eq!(gestures(&mut recognizer, &events), [Gesture::DoubleClick]);
+
+        // Triple clicks don't wait for another one.
+        let events =
+            [press(2000), release(2050), press(2100), release(2150), press(2200), release(2250)];
+        assert_eq!(gestures(&mut recognizer, &events), [Gesture::TripleClick]);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut recognizer = GestureRecognizer::new(TIMING);
+
+        let events =
+            [press(0), idle(599), idle(600), idle(799), idle(800), idle(1000), release(1100)];
+        assert_eq!(
+            gestures(&mut recognizer, &events),
+            [
+                Gesture::LongPressStart,
+                Gesture::LongPressRepeat,
+                Gesture::LongPressRepeat,
+                Gesture::LongPressEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn long_press_without_repeats() {
+        let mut recognizer =
+            GestureRecognizer::new(GestureTiming { long_press_repeat_ms: 0, ..TIMING });
+
+        let events = [press(0), idle(600), idle(5000), release(5000)];
+        assert_eq!(
+            gestures(&mut recognizer, &events),
+            [Gesture::LongPressStart, Gesture::LongPressEnd]
+        );
+    }
+
+    #[test]
+    fn cancelled_presses() {
+        let mut recognizer = GestureRecognizer::new(TIMING);
+
+        recognizer.update(Some(&ButtonEvent::Press), Instant::from_ms(0));
+        recognizer.cancel();
+        let events = [idle(1000), release(1100), idle(2000)];
+        assert!(gestures(&mut recognizer, &events).is_empty());
+
+        // Clicks before the cancelled press still count.
+        let events = [press(2100), release(2200), press(2300)];
+        assert!(gestures(&mut recognizer, &events).is_empty());
+        recognizer.cancel();
+        let events = [idle(2310), release(2400), idle(3000)];
+        assert_eq!(gestures(&mut recognizer, &events), [Gesture::Click]);
+
+        let events = [press(3000), release(3050), press(3100), release(3150), press(3200)];
+        assert!(gestures(&mut recognizer, &events).is_empty());
+        recognizer.cancel();
+        assert_eq!(gestures(&mut recognizer, &[release(3300)]), [Gesture::DoubleClick]);
+
+        // Long presses which have started still end.
+        let events = [press(4000), idle(4600)];
+        assert_eq!(gestures(&mut recognizer, &events), [Gesture::LongPressStart]);
+        recognizer.cancel();
+        assert_eq!(gestures(&mut recognizer, &[release(4700)]), [Gesture::LongPressEnd]);
+    }
+
+    struct FakePin {
+        low: bool,
+    }
+
+    impl InputPin for FakePin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(!self.low)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.low)
+        }
+    }
+
+    #[test]
+    fn debouncing() {
+        let pin = FakePin { low: false };
+        let mut debouncer = Debouncer::new(pin, Active::Low, 20, Instant::from_ms(0));
+
+        debouncer.pin.low = true;
+        debouncer.poll(Instant::from_ms(10));
+        assert!(!debouncer.is_pressed());
+        debouncer.poll(Instant::from_ms(20));
+        assert!(debouncer.is_pressed());
+
+        // A glitch shorter than the debounce time is ignored.
+        debouncer.pin.low = false;
+        debouncer.poll(Instant::from_ms(30));
+        debouncer.pin.low = true;
+        debouncer.poll(Instant::from_ms(40));
+        assert!(debouncer.is_pressed());
+    }
+
+    #[test]
+    fn debouncing_is_independent_of_the_poll_rate() {
+        let pin = FakePin { low: true };
+        let mut debouncer = Debouncer::new(pin, Active::Low, 20, Instant::from_ms(0));
+
+        for ms in 1..20 {
+            debouncer.poll(Instant::from_ms(ms));
+            assert!(!debouncer.is_pressed());
+        }
+        debouncer.poll(Instant::from_ms(20));
+        assert!(debouncer.is_pressed());
+    }
+
+    #[test]
+    fn debouncing_after_a_stall() {
+        let pin = FakePin { low: false };
+        let mut debouncer = Debouncer::new(pin, Active::Low, 20, Instant::from_ms(0));
+
+        // A single sample after the main loop was held up doesn't settle the output.
+        debouncer.pin.low = true;
+        debouncer.poll(Instant::from_ms(5000));
+        assert!(!debouncer.is_pressed());
+        debouncer.poll(Instant::from_ms(5000 + TICK_PERIOD_MS));
+        assert!(debouncer.is_pressed());
+    }
+}
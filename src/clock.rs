@@ -0,0 +1,79 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use cortex_m_rt::exception;
+use hal::rcc::Clocks;
+use stm32f4xx_hal as hal;
+
+// The firmware's sense of time for timeouts and debouncing: the SysTick interrupts are
+// counted, and the SysTick counter fills in the milliseconds in between. Unlike the DWT
+// cycle counter, SysTick keeps counting while the core sleeps. Its interrupt also wakes
+// the main loop from `wakeup::sleep()`, which bounds how long it sleeps, so USB and time
+// based work keep going without their own interrupts.
+const TICK_FREQUENCY_HZ: u32 = 100;
+
+/// The longest the main loop sleeps between polls.
+pub const TICK_PERIOD_MS: u32 = 1000 / TICK_FREQUENCY_HZ;
+
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// A point in time, with millisecond resolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instant {
+    ms: u32,
+}
+
+impl Instant {
+    pub fn from_ms(ms: u32) -> Self {
+        Self { ms }
+    }
+
+    /// The milliseconds from `earlier` to this instant. The clock wraps around after
+    /// 49 days, so anything waiting longer than that has to latch its result.
+    pub fn ms_since(&self, earlier: Instant) -> u32 {
+        self.ms.wrapping_sub(earlier.ms)
+    }
+
+    /// True once `ms` milliseconds have passed from `earlier` to this instant.
+    pub fn has_elapsed(&self, earlier: Instant, ms: u32) -> bool {
+        self.ms_since(earlier) >= ms
+    }
+}
+
+pub struct Clock {
+    _syst: SYST,
+    reload: u32,
+    cycles_per_ms: u32,
+}
+
+impl Clock {
+    pub fn start(mut syst: SYST, clocks: &Clocks) -> Self {
+        let reload = clocks.sysclk().0 / TICK_FREQUENCY_HZ - 1;
+
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(reload);
+        syst.clear_current();
+        syst.enable_interrupt();
+        syst.enable_counter();
+
+        Self { _syst: syst, reload, cycles_per_ms: clocks.sysclk().0 / 1000 }
+    }
+
+    pub fn now(&self) -> Instant {
+        // Read again if a tick came in between, the counter has started over since.
+        loop {
+            let ticks = TICKS.load(Ordering::Relaxed);
+            let cycles = self.reload - SYST::get_current();
+
+            if TICKS.load(Ordering::Relaxed) == ticks {
+                let ms =
+                    ticks.wrapping_mul(TICK_PERIOD_MS).wrapping_add(cycles / self.cycles_per_ms);
+                return Instant::from_ms(ms);
+            }
+        }
+    }
+}
+
+#[exception]
+fn SysTick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,256 @@
+use panel_protocol::ArrayVec;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    UsbError,
+};
+
+// A USB HID interface sending consumer control usages, the media keys found on keyboards.
+// Every operating system understands them without drivers, or our host daemon.
+const USB_CLASS_HID: u8 = 0x03;
+const HID_SUBCLASS_NONE: u8 = 0x00;
+const HID_PROTOCOL_NONE: u8 = 0x00;
+const HID_DESCRIPTOR: u8 = 0x21;
+const HID_REPORT_DESCRIPTOR: u8 = 0x22;
+const HID_VERSION: u16 = 0x0111;
+
+const GET_DESCRIPTOR: u8 = 0x06;
+const HID_GET_REPORT: u8 = 0x01;
+const HID_GET_IDLE: u8 = 0x02;
+const HID_SET_IDLE: u8 = 0x0A;
+
+const REPORT_LEN: usize = 2;
+const POLL_INTERVAL_MS: u8 = 10;
+
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,         // Usage Page (Consumer)
+    0x09, 0x01,         // Usage (Consumer Control)
+    0xA1, 0x01,         // Collection (Application)
+    0x15, 0x00,         //   Logical Minimum (0)
+    0x26, 0xFF, 0x03,   //   Logical Maximum (0x3FF)
+    0x19, 0x00,         //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03,   //   Usage Maximum (0x3FF)
+    0x75, 0x10,         //   Report Size (16)
+    0x95, 0x01,         //   Report Count (1)
+    0x81, 0x00,         //   Input (Data, Array, Absolute)
+    0xC0,               // End Collection
+];
+
+const USAGE_NONE: u16 = 0x00;
+const USAGE_PLAY_PAUSE: u16 = 0xCD;
+const USAGE_MUTE: u16 = 0xE2;
+const USAGE_VOLUME_UP: u16 = 0xE9;
+const USAGE_VOLUME_DOWN: u16 = 0xEA;
+
+// Key presses beyond this are dropped, a fast spin of the dial doesn't need them all.
+const MAX_PENDING_USAGES: usize = 16;
+
+/// When the dial and button act as media keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsumerControlMode {
+    Off,
+
+    /// Only while the host daemon doesn't have the serial port open, so the panel is a
+    /// plain volume knob on any machine without doubling up with the daemon.
+    WithoutHost,
+
+    Always,
+}
+
+impl ConsumerControlMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ConsumerControlMode::Off),
+            1 => Some(ConsumerControlMode::WithoutHost),
+            2 => Some(ConsumerControlMode::Always),
+            _ => None,
+        }
+    }
+}
+
+/// The media key a click of the dial button sends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClickAction {
+    Mute,
+    PlayPause,
+}
+
+impl ClickAction {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ClickAction::Mute),
+            1 => Some(ClickAction::PlayPause),
+            _ => None,
+        }
+    }
+
+    fn usage(&self) -> u16 {
+        match self {
+            ClickAction::Mute => USAGE_MUTE,
+            ClickAction::PlayPause => USAGE_PLAY_PAUSE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConsumerControlConfig {
+    pub mode: ConsumerControlMode,
+    pub click: ClickAction,
+}
+
+impl Default for ConsumerControlConfig {
+    fn default() -> Self {
+        Self { mode: ConsumerControlMode::WithoutHost, click: ClickAction::Mute }
+    }
+}
+
+pub struct ConsumerControl<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint: EndpointIn<'a, B>,
+    config: ConsumerControlConfig,
+    /// Usages waiting to be sent, each one as a press followed by a release.
+    pending: ArrayVec<[u16; MAX_PENDING_USAGES]>,
+    /// The first pending usage has been pressed, its release is next.
+    pressed: bool,
+}
+
+impl<'a, B: UsbBus> ConsumerControl<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            interface: alloc.interface(),
+            endpoint: alloc.interrupt(REPORT_LEN as u16, POLL_INTERVAL_MS),
+            config: ConsumerControlConfig::default(),
+            pending: ArrayVec::new(),
+            pressed: false,
+        }
+    }
+
+    pub fn set_config(&mut self, config: ConsumerControlConfig) {
+        self.config = config;
+    }
+
+    /// Turns the volume up or down by one step per detent.
+    pub fn turn(&mut self, diff: i8, host_connected: bool) {
+        let usage = if diff > 0 { USAGE_VOLUME_UP } else { USAGE_VOLUME_DOWN };
+
+        for _ in 0..diff.unsigned_abs() {
+            self.push(usage, host_connected);
+        }
+    }
+
+    /// Sends the configured click action.
+    pub fn click(&mut self, host_connected: bool) {
+        self.push(self.config.click.usage(), host_connected);
+    }
+
+    /// Sends the next report, if the endpoint is ready for it. Called after every poll.
+    pub fn write_pending(&mut self) {
+        let usage = match self.pending.first() {
+            Some(_) if self.pressed => USAGE_NONE,
+            Some(&usage) => usage,
+            None => return,
+        };
+
+        match self.endpoint.write(&usage.to_le_bytes()) {
+            Ok(_) => {
+                if self.pressed {
+                    self.pending.remove(0);
+                }
+                self.pressed = !self.pressed;
+            },
+            Err(UsbError::WouldBlock) => {},
+            // The interrupt endpoint doesn't work until the host has configured the device,
+            // and a key press from before then would be stale by the time it does.
+            Err(_) => {
+                self.pending.clear();
+                self.pressed = false;
+            },
+        }
+    }
+
+    fn push(&mut self, usage: u16, host_connected: bool) {
+        let active = match self.config.mode {
+            ConsumerControlMode::Off => false,
+            ConsumerControlMode::WithoutHost => !host_connected,
+            ConsumerControlMode::Always => true,
+        };
+
+        if active {
+            let _ = self.pending.try_push(usage);
+        }
+    }
+
+    fn is_for_us(&self, request: &Request) -> bool {
+        request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for ConsumerControl<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.interface, USB_CLASS_HID, HID_SUBCLASS_NONE, HID_PROTOCOL_NONE)?;
+
+        let version = HID_VERSION.to_le_bytes();
+        let report_descriptor_len = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        writer.write(
+            HID_DESCRIPTOR,
+            &[
+                version[0],
+                version[1],
+                0, // Country code
+                1, // Number of class descriptors
+                HID_REPORT_DESCRIPTOR,
+                report_descriptor_len[0],
+                report_descriptor_len[1],
+            ],
+        )?;
+
+        writer.endpoint(&self.endpoint)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = *xfer.request();
+        if !self.is_for_us(&request) {
+            return;
+        }
+
+        match (request.request_type, request.request) {
+            (RequestType::Standard, GET_DESCRIPTOR)
+                if (request.value >> 8) as u8 == HID_REPORT_DESCRIPTOR =>
+            {
+                xfer.accept_with_static(REPORT_DESCRIPTOR).ok();
+            },
+            (RequestType::Class, HID_GET_REPORT) => {
+                xfer.accept_with(&USAGE_NONE.to_le_bytes()).ok();
+            },
+            (RequestType::Class, HID_GET_IDLE) => {
+                xfer.accept_with(&[0]).ok();
+            },
+            (RequestType::Class, _) => {
+                xfer.reject().ok();
+            },
+            _ => {},
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = *xfer.request();
+        if !self.is_for_us(&request) || request.request_type != RequestType::Class {
+            return;
+        }
+
+        match request.request {
+            // Reports are only sent on changes anyway.
+            HID_SET_IDLE => {
+                xfer.accept().ok();
+            },
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+}
@@ -1,36 +1,304 @@
-use hal::{prelude::*, qei::Qei, stm32::TIM1};
-use stm32f4xx_hal as hal;
+use crate::{
+    clock::Instant,
+    encoder::{EncoderConfig, QeiTimer},
+};
 
-pub struct Counter<PINS> {
-    qei: Qei<TIM1, PINS>,
-    last_count: u16,
+// Detents further apart than this are never accelerated.
+const ACCELERATION_WINDOW_MS: u32 = 100;
+const DEFAULT_MAX_MULTIPLIER: u8 = 4;
+const DEFAULT_COUNTS_PER_DETENT: u8 = 2;
+
+/// How the speed of the dial maps onto a multiplier for each detent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccelerationCurve {
+    None,
+    Linear,
+    Exponential,
+}
+
+impl AccelerationCurve {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(AccelerationCurve::None),
+            1 => Some(AccelerationCurve::Linear),
+            2 => Some(AccelerationCurve::Exponential),
+            _ => None,
+        }
+    }
+
+    /// `speed` goes from 0.0 (slow) to 1.0 (as fast as we can measure).
+    fn multiplier(&self, speed: f32, max_multiplier: f32) -> f32 {
+        match self {
+            AccelerationCurve::None => 1.0,
+            AccelerationCurve::Linear => 1.0 + speed * (max_multiplier - 1.0),
+            AccelerationCurve::Exponential => libm::powf(max_multiplier, speed),
+        }
+    }
+}
+
+/// What to do when more detents accumulate than fit in a single report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetentOverflow {
+    /// Report the maximum and drop the rest.
+    Saturate,
+
+    /// Report the maximum and carry the rest over to the following polls.
+    Chunk,
 }
 
-impl<PINS> Counter<PINS> {
-    pub fn new(qei: Qei<TIM1, PINS>) -> Self {
-        unsafe {
-            // TODO(bschwind) - Expose this functionality with a safe interface
-            //                  in stm32f4xx-hal.
-            // Change the mode of the QEI decoder to mode 1:
-            // Counter counts up/down on TI2FP1 edge depending on TI1FP2 level.
-            // Or in layman's terms, the encoder counts up and down on encoder
-            // pin A edges, while referencing the state of encoder pin B.
-            (*TIM1::ptr()).smcr.write(|w| w.sms().encoder_mode_1());
+impl DetentOverflow {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(DetentOverflow::Saturate),
+            1 => Some(DetentOverflow::Chunk),
+            _ => None,
         }
+    }
+}
+
+/// The movement of the dial since the last poll.
+#[derive(Debug, Clone, Copy)]
+pub struct Detents {
+    /// The number of detents the dial actually moved.
+    pub raw: i8,
+
+    /// The movement scaled up according to the acceleration curve.
+    pub accelerated: i8,
+}
+
+pub struct Counter<Q: QeiTimer> {
+    qei: Q,
+    last_count: u16,
+    counts_per_detent: u8,
+    overflow: DetentOverflow,
+    // Encoder counts which don't add up to a whole detent yet.
+    residual_counts: i32,
+    // Whole detents which haven't been reported yet.
+    pending_detents: i32,
+    last_detent: Option<Instant>,
+    curve: AccelerationCurve,
+    max_multiplier: u8,
+}
+
+impl<Q: QeiTimer> Counter<Q> {
+    pub fn new(mut qei: Q) -> Self {
+        // By default the encoder counts up and down on encoder pin A edges,
+        // while referencing the state of encoder pin B.
+        qei.configure(&EncoderConfig::default());
 
         let last_count = qei.count();
-        Counter { qei, last_count }
+
+        Counter {
+            qei,
+            last_count,
+            counts_per_detent: DEFAULT_COUNTS_PER_DETENT,
+            overflow: DetentOverflow::Chunk,
+            residual_counts: 0,
+            pending_detents: 0,
+            last_detent: None,
+            curve: AccelerationCurve::Linear,
+            max_multiplier: DEFAULT_MAX_MULTIPLIER,
+        }
+    }
+
+    /// Reconfigures the encoder interface. Counts from before the change are discarded.
+    pub fn set_encoder_config(&mut self, config: &EncoderConfig) {
+        self.qei.configure(config);
+        self.last_count = self.qei.count();
+        self.residual_counts = 0;
+    }
+
+    /// Sets the acceleration curve and the cap on how much a single detent
+    /// can be multiplied by.
+    pub fn set_acceleration(&mut self, curve: AccelerationCurve, max_multiplier: u8) {
+        self.curve = curve;
+        self.max_multiplier = max_multiplier.max(1);
+    }
+
+    /// Sets how many encoder counts make up one detent, which depends on the
+    /// encoder model. Only 1, 2 and 4 are valid, other values are ignored.
+    pub fn set_resolution(&mut self, counts_per_detent: u8, overflow: DetentOverflow) {
+        if matches!(counts_per_detent, 1 | 2 | 4) {
+            self.counts_per_detent = counts_per_detent;
+            self.residual_counts = 0;
+        }
+
+        self.overflow = overflow;
     }
 
-    pub fn poll(&mut self) -> Option<i8> {
+    pub fn poll(&mut self, now: Instant) -> Option<Detents> {
         let count = self.qei.count();
         let diff = count.wrapping_sub(self.last_count) as i16;
+        self.last_count = count;
+
+        // Carry partial detents over exactly instead of truncating them.
+        self.residual_counts += diff as i32;
+        let detents = self.residual_counts / self.counts_per_detent as i32;
+        self.residual_counts -= detents * self.counts_per_detent as i32;
+        self.pending_detents += detents;
+
+        let raw = self.pending_detents.clamp(i8::MIN as i32, i8::MAX as i32);
+        self.pending_detents = match self.overflow {
+            DetentOverflow::Saturate => 0,
+            DetentOverflow::Chunk => self.pending_detents - raw,
+        };
+
+        if raw != 0 {
+            let raw = raw as i8;
+
+            let speed = match self.last_detent {
+                Some(last) => {
+                    let elapsed = now.ms_since(last).min(ACCELERATION_WINDOW_MS);
+                    1.0 - (elapsed as f32 / ACCELERATION_WINDOW_MS as f32)
+                },
+                None => 0.0,
+            };
+            self.last_detent = Some(now);
 
-        if diff.abs() >= 2 {
-            self.last_count = count;
-            Some((diff / 2) as i8)
+            let multiplier = self.curve.multiplier(speed, self.max_multiplier as f32);
+            let accelerated =
+                libm::roundf(raw as f32 * multiplier).clamp(i8::MIN as f32, i8::MAX as f32) as i8;
+
+            Some(Detents { raw, accelerated })
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeQei {
+        count: u16,
+    }
+
+    impl QeiTimer for FakeQei {
+        fn count(&self) -> u16 {
+            self.count
+        }
+
+        fn configure(&mut self, _config: &EncoderConfig) {}
+    }
+
+    /// Turns the dial by `counts` and polls it at `now` milliseconds.
+    fn turn(counter: &mut Counter<FakeQei>, counts: i16, now: u32) -> Option<Detents> {
+        counter.qei.count = counter.qei.count.wrapping_add(counts as u16);
+        counter.poll(Instant::from_ms(now))
+    }
+
+    /// A counter without acceleration, so only the detents count.
+    fn plain_counter(
+        start: u16,
+        counts_per_detent: u8,
+        overflow: DetentOverflow,
+    ) -> Counter<FakeQei> {
+        let mut counter = Counter::new(FakeQei { count: start });
+        counter.set_resolution(counts_per_detent, overflow);
+        counter.set_acceleration(AccelerationCurve::None, 1);
+        counter
+    }
+
+    fn raw(detents: Option<Detents>) -> Option<i8> {
+        detents.map(|detents| detents.raw)
+    }
+
+    #[test]
+    fn partial_detents_carry_over() {
+        let mut counter = plain_counter(100, 4, DetentOverflow::Chunk);
+
+        assert_eq!(raw(turn(&mut counter, 3, 0)), None);
+        assert_eq!(raw(turn(&mut counter, 1, 0)), Some(1));
+        assert_eq!(raw(turn(&mut counter, 6, 0)), Some(1));
+        assert_eq!(raw(turn(&mut counter, 2, 0)), Some(1));
+
+        assert_eq!(raw(turn(&mut counter, -3, 0)), None);
+        assert_eq!(raw(turn(&mut counter, 2, 0)), None);
+        assert_eq!(raw(turn(&mut counter, -3, 0)), Some(-1));
+    }
+
+    #[test]
+    fn counter_wraps_around() {
+        let mut counter = plain_counter(u16::MAX - 1, 2, DetentOverflow::Chunk);
+
+        assert_eq!(raw(turn(&mut counter, 4, 0)), Some(2));
+        assert_eq!(raw(turn(&mut counter, -4, 0)), Some(-2));
+    }
+
+    #[test]
+    fn resolution_change_drops_partial_detents() {
+        let mut counter = plain_counter(0, 4, DetentOverflow::Chunk);
+
+        assert_eq!(raw(turn(&mut counter, 3, 0)), None);
+        counter.set_resolution(2, DetentOverflow::Chunk);
+        assert_eq!(raw(turn(&mut counter, 1, 0)), None);
+        assert_eq!(raw(turn(&mut counter, 1, 0)), Some(1));
+
+        // Only 1, 2 and 4 counts per detent are valid.
+        counter.set_resolution(3, DetentOverflow::Chunk);
+        assert_eq!(counter.counts_per_detent, 2);
+    }
+
+    #[test]
+    fn overflow_is_chunked() {
+        let mut counter = plain_counter(0, 1, DetentOverflow::Chunk);
+
+        assert_eq!(raw(turn(&mut counter, 200, 0)), Some(127));
+        assert_eq!(raw(turn(&mut counter, 0, 0)), Some(73));
+        assert_eq!(raw(turn(&mut counter, 0, 0)), None);
+
+        assert_eq!(raw(turn(&mut counter, -300, 0)), Some(-128));
+        assert_eq!(raw(turn(&mut counter, 0, 0)), Some(-128));
+        assert_eq!(raw(turn(&mut counter, 0, 0)), Some(-44));
+    }
+
+    #[test]
+    fn overflow_is_saturated() {
+        let mut counter = plain_counter(0, 1, DetentOverflow::Saturate);
+
+        assert_eq!(raw(turn(&mut counter, 200, 0)), Some(127));
+        assert_eq!(raw(turn(&mut counter, 0, 0)), None);
+    }
+
+    #[test]
+    fn quick_detents_are_accelerated() {
+        let mut counter = Counter::new(FakeQei { count: 0 });
+        counter.set_acceleration(AccelerationCurve::Linear, 4);
+
+        let detents = turn(&mut counter, 2, 1000).unwrap();
+        assert_eq!((detents.raw, detents.accelerated), (1, 1));
+
+        // Right after the last detent, the full multiplier applies.
+        let detents = turn(&mut counter, 4, 1000).unwrap();
+        assert_eq!((detents.raw, detents.accelerated), (2, 8));
+
+        // Halfway through the acceleration window, half of it does.
+        let detents = turn(&mut counter, 2, 1000 + ACCELERATION_WINDOW_MS / 2).unwrap();
+        assert_eq!((detents.raw, detents.accelerated), (1, 3));
+
+        // Outside the window, none does.
+        let detents = turn(&mut counter, -2, 1050 + ACCELERATION_WINDOW_MS).unwrap();
+        assert_eq!((detents.raw, detents.accelerated), (-1, -1));
+    }
+
+    #[test]
+    fn acceleration_curves() {
+        assert_eq!(AccelerationCurve::None.multiplier(1.0, 4.0), 1.0);
+        assert_eq!(AccelerationCurve::Linear.multiplier(0.0, 4.0), 1.0);
+        assert_eq!(AccelerationCurve::Linear.multiplier(1.0, 4.0), 4.0);
+        assert_eq!(AccelerationCurve::Exponential.multiplier(0.0, 4.0), 1.0);
+        assert!(libm::fabsf(AccelerationCurve::Exponential.multiplier(0.5, 4.0) - 2.0) < 1e-6);
+        assert_eq!(AccelerationCurve::Exponential.multiplier(1.0, 4.0), 4.0);
+    }
+
+    #[test]
+    fn accelerated_movement_saturates() {
+        let mut counter = Counter::new(FakeQei { count: 0 });
+        counter.set_acceleration(AccelerationCurve::Linear, 10);
+
+        turn(&mut counter, 2, 0);
+        let detents = turn(&mut counter, 100, 0).unwrap();
+        assert_eq!((detents.raw, detents.accelerated), (50, i8::MAX));
+    }
+}
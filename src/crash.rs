@@ -0,0 +1,125 @@
+use crate::{
+    backup_domain::{BackupDomain, BOOT_REASON, CRASH_FILE, CRASH_HEADER, CRASH_MESSAGE},
+    reset_cause::BootReason,
+};
+use core::{fmt::Write, panic::PanicInfo};
+
+// The crash record is kept in the backup registers, as the F411 has no backup SRAM:
+//   CRASH_HEADER:   CRASH_MAGIC in the upper 16 bits, the line number in the lower 16.
+//   CRASH_FILE:     the end of the source file path.
+//   CRASH_MESSAGE:  the start of the panic message, empty without the panic-message feature.
+const CRASH_MAGIC: u32 = 0xC4A5;
+const FILE_LEN: usize = CRASH_FILE.byte_len();
+const MESSAGE_LEN: usize = CRASH_MESSAGE.byte_len();
+
+/// A fixed size text buffer which silently drops whatever doesn't fit.
+struct TextBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    fn new() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        // Truncation might have cut a character in half, keep the part before it.
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(text) => text,
+            Err(e) => core::str::from_utf8(&self.bytes[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl<const N: usize> Write for TextBuffer<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let count = s.len().min(N - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// The panic which caused the previous reset.
+pub struct CrashRecord {
+    line: u16,
+    file: TextBuffer<FILE_LEN>,
+    message: TextBuffer<MESSAGE_LEN>,
+}
+
+impl CrashRecord {
+    pub fn line(&self) -> u16 {
+        self.line
+    }
+
+    pub fn file(&self) -> &str {
+        self.file.as_str()
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+/// Reads the crash record left by the panic handler, if there is one.
+pub fn read_crash_record(backup_domain: &BackupDomain) -> Option<CrashRecord> {
+    let header = backup_domain.read(&CRASH_HEADER);
+    if header >> 16 != CRASH_MAGIC {
+        return None;
+    }
+
+    let mut file = TextBuffer::new();
+    backup_domain.read_block(&CRASH_FILE, &mut file.bytes);
+    file.len = text_len(&file.bytes);
+
+    let mut message = TextBuffer::new();
+    backup_domain.read_block(&CRASH_MESSAGE, &mut message.bytes);
+    message.len = text_len(&message.bytes);
+
+    Some(CrashRecord { line: header as u16, file, message })
+}
+
+/// Clears the crash record, once the host has been told about it.
+pub fn clear_crash_record(backup_domain: &mut BackupDomain) {
+    backup_domain.write(&CRASH_HEADER, 0);
+}
+
+/// The length of zero padded text.
+fn text_len(bytes: &[u8]) -> usize {
+    bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len())
+}
+
+#[cfg_attr(not(test), panic_handler)]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    // Safety: Interrupts are disabled and the main loop will never run again,
+    // so nothing else can be using the backup domain.
+    let mut backup_domain = unsafe { BackupDomain::steal() };
+
+    let (file, line) = match info.location() {
+        Some(location) => (location.file(), location.line()),
+        None => ("", 0),
+    };
+
+    // The end of the path is the interesting part.
+    let start = (file.len().saturating_sub(FILE_LEN)..file.len())
+        .find(|&i| file.is_char_boundary(i))
+        .unwrap_or(file.len());
+    let mut file_buffer = TextBuffer::<FILE_LEN>::new();
+    let _ = file_buffer.write_str(&file[start..]);
+
+    #[allow(unused_mut)]
+    let mut message = TextBuffer::<MESSAGE_LEN>::new();
+    // Without the "panicked at" prefix, the location is already in the record.
+    #[cfg(feature = "panic-message")]
+    let _ = write!(message, "{}", info.message());
+
+    backup_domain.write_block(&CRASH_FILE, &file_buffer.bytes[..file_buffer.len]);
+    backup_domain.write_block(&CRASH_MESSAGE, &message.bytes[..message.len]);
+    backup_domain.write(&CRASH_HEADER, (CRASH_MAGIC << 16) | line.min(u16::MAX as u32));
+    backup_domain.write(&BOOT_REASON, BootReason::Panic);
+
+    cortex_m::peripheral::SCB::sys_reset();
+}
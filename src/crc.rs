@@ -0,0 +1,82 @@
+// Bitwise implementations, trading speed for flash space.
+
+/// CRC-32 (IEEE 802.3), the same as zlib's `crc32()`.
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { value: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.value ^= byte as u32;
+
+            for _ in 0..8 {
+                let mask = (self.value & 1).wrapping_neg();
+                self.value = (self.value >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// CRC-16/CCITT-FALSE, as in Python's `binascii.crc_hqx(data, 0xFFFF)`.
+pub struct Crc16 {
+    value: u16,
+}
+
+impl Crc16 {
+    pub fn new() -> Self {
+        Self { value: 0xFFFF }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.value ^= (byte as u16) << 8;
+
+            for _ in 0..8 {
+                let mask = (self.value >> 15).wrapping_neg();
+                self.value = (self.value << 1) ^ (0x1021 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u16 {
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK_INPUT: &[u8] = b"123456789";
+
+    #[test]
+    fn crc32_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(CHECK_INPUT);
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc32_in_pieces() {
+        let mut crc = Crc32::new();
+        crc.update(&CHECK_INPUT[..4]);
+        crc.update(&CHECK_INPUT[4..]);
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        let mut crc = Crc16::new();
+        crc.update(CHECK_INPUT);
+        assert_eq!(crc.finish(), 0x29B1);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::bootload;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+};
+
+// A USB DFU 1.1 runtime interface, which lets standard tools like `dfu-util -e` detach the
+// panel into the ST bootloader, where the actual DFU download happens.
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+// The device detaches by itself, without waiting for a USB reset, and the bootloader it
+// detaches into accepts downloads.
+const ATTRIBUTE_WILL_DETACH: u8 = 1 << 3;
+const ATTRIBUTE_CAN_DOWNLOAD: u8 = 1 << 0;
+const DETACH_TIMEOUT_MS: u16 = 1000;
+// Only meaningful in DFU mode, this is the ST bootloader's.
+const TRANSFER_SIZE: u16 = 2048;
+const DFU_VERSION: u16 = 0x011A;
+
+const DFU_DETACH: u8 = 0;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_GETSTATE: u8 = 5;
+
+const STATUS_OK: u8 = 0;
+const STATE_APP_IDLE: u8 = 0;
+
+pub struct DfuRuntime {
+    interface: InterfaceNumber,
+    detach_requested: bool,
+}
+
+impl DfuRuntime {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        Self { interface: alloc.interface(), detach_requested: false }
+    }
+
+    /// Returns true once after the host has asked the device to detach.
+    pub fn take_detach_request(&mut self) -> bool {
+        core::mem::replace(&mut self.detach_requested, false)
+    }
+
+    fn is_for_us(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.interface,
+            USB_CLASS_APPLICATION_SPECIFIC,
+            DFU_SUBCLASS,
+            DFU_PROTOCOL_RUNTIME,
+        )?;
+
+        let detach_timeout = DETACH_TIMEOUT_MS.to_le_bytes();
+        let transfer_size = TRANSFER_SIZE.to_le_bytes();
+        let version = DFU_VERSION.to_le_bytes();
+        writer.write(
+            DFU_FUNCTIONAL_DESCRIPTOR,
+            &[
+                ATTRIBUTE_WILL_DETACH | ATTRIBUTE_CAN_DOWNLOAD,
+                detach_timeout[0],
+                detach_timeout[1],
+                transfer_size[0],
+                transfer_size[1],
+                version[0],
+                version[1],
+            ],
+        )
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_for_us(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            DFU_DETACH if bootload::BOOTLOADER_ALLOWED => {
+                // The detach happens in the main loop, once this request has completed.
+                self.detach_requested = true;
+                xfer.accept().ok();
+            },
+            // Including detaches in release builds.
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_for_us(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            DFU_GETSTATUS => {
+                // Status, a 3 byte poll timeout, the state and the status string index.
+                xfer.accept_with(&[STATUS_OK, 0, 0, 0, STATE_APP_IDLE, 0]).ok();
+            },
+            DFU_GETSTATE => {
+                xfer.accept_with(&[STATE_APP_IDLE]).ok();
+            },
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use hal::{
+    qei::Qei,
+    stm32::{TIM1, TIM2, TIM3, TIM4, TIM5},
+};
+use stm32f4xx_hal as hal;
+
+// TIMx_SMCR: slave mode selection.
+const SMCR_SMS_MASK: u32 = 0b111;
+// TIMx_CCMR1: input capture filters for channels 1 and 2.
+const CCMR1_IC1F_SHIFT: u32 = 4;
+const CCMR1_IC2F_SHIFT: u32 = 12;
+const CCMR1_ICF_MASK: u32 = 0b1111;
+// TIMx_CCER: input polarity for channels 1 and 2.
+const CCER_CC1P: u32 = 1 << 1;
+const CCER_CC2P: u32 = 1 << 5;
+
+/// Which encoder edges make the counter count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncoderMode {
+    /// Count on the edges of input 1 (encoder pin A), using the level of
+    /// input 2 for the direction.
+    Ti1,
+
+    /// Count on the edges of input 2 (encoder pin B), using the level of
+    /// input 1 for the direction.
+    Ti2,
+
+    /// Count on the edges of both inputs.
+    BothEdges,
+}
+
+impl EncoderMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(EncoderMode::Ti1),
+            1 => Some(EncoderMode::Ti2),
+            2 => Some(EncoderMode::BothEdges),
+            _ => None,
+        }
+    }
+
+    /// The value of TIMx_SMCR.SMS for this mode.
+    fn sms_bits(&self) -> u32 {
+        match self {
+            EncoderMode::Ti1 => 0b001,
+            EncoderMode::Ti2 => 0b010,
+            EncoderMode::BothEdges => 0b011,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderConfig {
+    pub mode: EncoderMode,
+
+    /// The digital input filter applied to both inputs, from 0 (off) to 15.
+    /// See the description of TIMx_CCMR1.IC1F in the reference manual.
+    pub filter: u8,
+
+    pub invert_a: bool,
+    pub invert_b: bool,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self { mode: EncoderMode::Ti1, filter: 0, invert_a: false, invert_b: false }
+    }
+}
+
+/// A timer running as a quadrature encoder interface.
+pub trait QeiTimer {
+    /// The current count, truncated to 16 bits for timers with wider counters.
+    fn count(&self) -> u16;
+
+    fn configure(&mut self, config: &EncoderConfig);
+}
+
+macro_rules! qei_timer {
+    ($($TIM:ident,)+) => {
+        $(
+            impl<PINS> QeiTimer for Qei<$TIM, PINS> {
+                fn count(&self) -> u16 {
+                    // TIM2 and TIM5 have 32 bit counters, the others 16 bits.
+                    let count: u32 = embedded_hal::Qei::count(self).into();
+                    count as u16
+                }
+
+                fn configure(&mut self, config: &EncoderConfig) {
+                    let filter = config.filter.min(15) as u32;
+                    let polarity = if config.invert_a { CCER_CC1P } else { 0 }
+                        | if config.invert_b { CCER_CC2P } else { 0 };
+
+                    // Safety: The Qei owns the timer, and we hold a unique reference to the
+                    // Qei, so nothing else is touching these registers. The bits written are
+                    // the documented encoder mode, input filter and polarity fields.
+                    unsafe {
+                        let tim = &*$TIM::ptr();
+
+                        tim.smcr.modify(|r, w| {
+                            w.bits((r.bits() & !SMCR_SMS_MASK) | config.mode.sms_bits())
+                        });
+                        tim.ccmr1_input().modify(|r, w| {
+                            let mask = (CCMR1_ICF_MASK << CCMR1_IC1F_SHIFT)
+                                | (CCMR1_ICF_MASK << CCMR1_IC2F_SHIFT);
+                            w.bits(
+                                (r.bits() & !mask)
+                                    | (filter << CCMR1_IC1F_SHIFT)
+                                    | (filter << CCMR1_IC2F_SHIFT),
+                            )
+                        });
+                        tim.ccer.modify(|r, w| {
+                            w.bits((r.bits() & !(CCER_CC1P | CCER_CC2P)) | polarity)
+                        });
+                    }
+                }
+            }
+        )+
+    }
+}
+
+qei_timer! {
+    TIM1,
+    TIM2,
+    TIM3,
+    TIM4,
+    TIM5,
+}
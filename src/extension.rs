@@ -0,0 +1,762 @@
+use crate::{
+    button::{Gesture, GestureTiming},
+    consumer_control::{ClickAction, ConsumerControlConfig, ConsumerControlMode},
+    counter::{AccelerationCurve, DetentOverflow},
+    encoder::{EncoderConfig, EncoderMode},
+    failsafe::{FailsafeConfig, FailsafeReason, FailsafeScene},
+    framing::FrameError,
+    input::ButtonId,
+    power::{SuspendBehavior, SuspendLights},
+    reset_cause::BootReason,
+    serial::Error,
+    slots::SlotId,
+    update::{UpdateError, SIGNATURE_LEN, UPDATE_CHUNK_LEN},
+};
+use panel_protocol::ArrayVec;
+
+// Commands and reports which aren't (yet) part of panel-protocol are carried on the
+// same serial stream in their own frames. A frame looks like:
+//
+//   [EXT_FRAME_MARKER, kind, payload_len, payload...]
+//
+// Multi-byte integers in the payload are big-endian. No panel-protocol command starts with
+// EXT_FRAME_MARKER, so extension frames can be sent between commands in the same write.
+pub const EXT_FRAME_MARKER: u8 = 0xFE;
+
+const EXT_HEADER_LEN: usize = 3;
+// The largest payload is a firmware update chunk with its offset.
+const MAX_EXT_PAYLOAD_LEN: usize = 4 + UPDATE_CHUNK_LEN;
+pub const MAX_EXT_FRAME_LEN: usize = EXT_HEADER_LEN + MAX_EXT_PAYLOAD_LEN;
+
+/// The newest protocol spoken: panel-protocol 0.4 commands and reports, plus the
+/// extension reports.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// The protocol spoken until a host asks for another one with `ExtCommand::Hello`:
+/// panel-protocol 0.4 without the extension reports, which hosts from before the
+/// extensions can't parse. Every version encodes panel-protocol 0.4 the same way, so
+/// this doesn't help hosts built against another panel-protocol rev.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// The kinds of the extension commands handled by the firmware, see `ExtCommand::parse()`.
+const SUPPORTED_COMMANDS: &[u8] = b"VvbaregDsChfwUcFAMH";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtCommand {
+    /// Let the firmware own an absolute volume level instead of only
+    /// forwarding relative dial turns.
+    SetVolumeMode { enabled: bool },
+
+    /// Overwrite the current volume level, to sync it with the host.
+    SetVolumeLevel { level: u16 },
+
+    /// Configure the range of the volume level and how far one detent moves it.
+    SetVolumeBounds { min: u16, max: u16, step: u16 },
+
+    /// Choose how quick dial turns are accelerated, and whether dial value
+    /// reports carry the raw or the accelerated movement.
+    SetDialAcceleration {
+        dial: u8,
+        curve: AccelerationCurve,
+        max_multiplier: u8,
+        report_accelerated: bool,
+    },
+
+    /// Configure a dial for a particular encoder model.
+    SetDialResolution { dial: u8, counts_per_detent: u8, overflow: DetentOverflow },
+
+    /// Configure the timer decoding a dial's encoder signals.
+    SetEncoderConfig { dial: u8, config: EncoderConfig },
+
+    /// Configure the thresholds used to recognize a button's gestures.
+    SetGestureTiming { button: ButtonId, timing: GestureTiming },
+
+    /// Set how long a button's input must be stable before a change is accepted.
+    SetDebounceTime { button: ButtonId, debounce_time_ms: u16 },
+
+    /// Configure when the dial and its button act as media keys.
+    SetConsumerControl { config: ConsumerControlConfig },
+
+    /// Configure what the lights do while the host has suspended the USB bus.
+    SetSuspendBehavior { behavior: SuspendBehavior },
+
+    /// Tells the firmware the host is still alive. Any other command does too.
+    Heartbeat,
+
+    /// Configure what happens when the host stops responding.
+    SetFailsafe { config: FailsafeConfig },
+
+    /// Change how long the main loop may stall before the watchdog resets the MCU.
+    SetWatchdogTimeout { timeout_ms: u16 },
+
+    /// Start a firmware update with an image of `size` bytes and its CRC-32.
+    UpdateBegin { size: u32, crc: u32 },
+
+    /// The next part of the firmware image, starting `offset` bytes into it.
+    /// Only the first `len` bytes of `data` are used.
+    UpdateChunk { offset: u32, len: u8, data: [u8; UPDATE_CHUNK_LEN] },
+
+    /// The whole image has been sent, verify it against its signature and install it.
+    /// Images without a signature are rejected.
+    UpdateFinish { signature: Option<[u8; SIGNATURE_LEN]> },
+
+    /// Abandon the firmware update in progress.
+    UpdateAbort,
+
+    /// Switch the stream this arrives on to or from the framed mode, see `framing`.
+    /// Handled by `SerialProtocol` itself, it never reaches the main loop.
+    SetFraming { framed: bool },
+
+    /// The host introduces itself with the newest protocol version it speaks. Answered
+    /// with `ExtReport::Hello`, and handled by `SerialProtocol` itself like `SetFraming`.
+    Hello { version: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtReport {
+    /// The absolute volume level, sent whenever it changes or a host connects.
+    VolumeLevel { level: u16 },
+
+    /// Movement of a dial other than the main volume dial, which keeps using
+    /// `Report::DialValue`.
+    DialValue { dial: u8, diff: i8 },
+
+    /// Movement of the main dial while its button is held down.
+    PressTurn { diff: i8 },
+
+    /// Sent after `Report::Release`, telling the host whether the dial was turned
+    /// while the button was held (a press-turn) or not (a plain click).
+    PressReleased { press_turn: bool },
+
+    /// A button other than the dial button, which keeps using `Report::Press`,
+    /// has been pressed.
+    ButtonPress { button: ButtonId },
+
+    /// A button other than the dial button has been released.
+    ButtonRelease { button: ButtonId },
+
+    /// A click, multi-click or long press of a button.
+    Gesture { button: ButtonId, gesture: Gesture },
+
+    /// The failsafe scene was switched to while the host was away.
+    FailsafeTriggered { reason: FailsafeReason },
+
+    /// Why the panel last booted, sent whenever a host connects. `reset_flags` is a
+    /// combination of the `reset_cause::RESET_*` bits.
+    Startup { reset_flags: u8, boot_reason: BootReason },
+
+    /// The result of a firmware update command, along with the number of bytes of
+    /// the image written so far.
+    UpdateStatus { error: Option<UpdateError>, written: u32 },
+
+    /// The application slot the firmware is running from, sent whenever a host connects.
+    /// `on_trial` is true until a freshly installed firmware has heard from the host,
+    /// `rolled_back` if the previous update never did.
+    FirmwareSlot { slot: SlotId, on_trial: bool, rolled_back: bool },
+
+    /// In framed mode, the command frame with sequence number `seq` arrived intact.
+    Ack { seq: u8 },
+
+    /// In framed mode, the command frame with sequence number `seq` was rejected.
+    Nack { seq: u8, error: FrameError },
+
+    /// The answer to `ExtCommand::Hello`: the protocol version the stream speaks from now
+    /// on, or 0 if the host's is too old, followed by the range of versions the firmware
+    /// speaks, its own version and the kinds of extension commands it handles.
+    Hello { version: u8 },
+}
+
+impl ExtCommand {
+    fn parse(kind: u8, payload: &[u8]) -> Result<Self, Error> {
+        let command = match (kind, payload) {
+            (b'V', [enabled]) => ExtCommand::SetVolumeMode { enabled: *enabled != 0 },
+            (b'v', [l0, l1]) => {
+                ExtCommand::SetVolumeLevel { level: u16::from_be_bytes([*l0, *l1]) }
+            },
+            (b'b', [min0, min1, max0, max1, step0, step1]) => ExtCommand::SetVolumeBounds {
+                min: u16::from_be_bytes([*min0, *min1]),
+                max: u16::from_be_bytes([*max0, *max1]),
+                step: u16::from_be_bytes([*step0, *step1]),
+            },
+            (b'a', [dial, curve, max_multiplier, report_accelerated]) => {
+                ExtCommand::SetDialAcceleration {
+                    dial: *dial,
+                    curve: AccelerationCurve::from_u8(*curve).ok_or(Error::MalformedMessage)?,
+                    max_multiplier: *max_multiplier,
+                    report_accelerated: *report_accelerated != 0,
+                }
+            },
+            (b'r', [dial, counts_per_detent, overflow]) => ExtCommand::SetDialResolution {
+                dial: *dial,
+                counts_per_detent: *counts_per_detent,
+                overflow: DetentOverflow::from_u8(*overflow).ok_or(Error::MalformedMessage)?,
+            },
+            (b'e', [dial, mode, filter, inversion]) => ExtCommand::SetEncoderConfig {
+                dial: *dial,
+                config: EncoderConfig {
+                    mode: EncoderMode::from_u8(*mode).ok_or(Error::MalformedMessage)?,
+                    filter: *filter,
+                    invert_a: inversion & 0b01 != 0,
+                    invert_b: inversion & 0b10 != 0,
+                },
+            },
+            (b'g', [button, mc0, mc1, lp0, lp1, rep0, rep1]) => ExtCommand::SetGestureTiming {
+                button: *button,
+                timing: GestureTiming {
+                    multi_click_ms: u16::from_be_bytes([*mc0, *mc1]),
+                    long_press_ms: u16::from_be_bytes([*lp0, *lp1]),
+                    long_press_repeat_ms: u16::from_be_bytes([*rep0, *rep1]),
+                },
+            },
+            (b'D', [button, ms0, ms1]) => ExtCommand::SetDebounceTime {
+                button: *button,
+                debounce_time_ms: u16::from_be_bytes([*ms0, *ms1]),
+            },
+            (b's', [lights, wake_on_button]) => ExtCommand::SetSuspendBehavior {
+                behavior: SuspendBehavior {
+                    lights: SuspendLights::from_u8(*lights).ok_or(Error::MalformedMessage)?,
+                    wake_on_button: *wake_on_button != 0,
+                },
+            },
+            (b'C', [mode, click]) => ExtCommand::SetConsumerControl {
+                config: ConsumerControlConfig {
+                    mode: ConsumerControlMode::from_u8(*mode).ok_or(Error::MalformedMessage)?,
+                    click: ClickAction::from_u8(*click).ok_or(Error::MalformedMessage)?,
+                },
+            },
+            (b'h', []) => ExtCommand::Heartbeat,
+            (b'f', [enabled, t0, t1, b0, b1, r, g, b]) => ExtCommand::SetFailsafe {
+                config: FailsafeConfig {
+                    enabled: *enabled != 0,
+                    timeout_ms: u16::from_be_bytes([*t0, *t1]),
+                    scene: FailsafeScene {
+                        brightness: u16::from_be_bytes([*b0, *b1]),
+                        led_r: *r,
+                        led_g: *g,
+                        led_b: *b,
+                    },
+                },
+            },
+            (b'w', [t0, t1]) => {
+                ExtCommand::SetWatchdogTimeout { timeout_ms: u16::from_be_bytes([*t0, *t1]) }
+            },
+            (b'U', [s0, s1, s2, s3, c0, c1, c2, c3]) => ExtCommand::UpdateBegin {
+                size: u32::from_be_bytes([*s0, *s1, *s2, *s3]),
+                crc: u32::from_be_bytes([*c0, *c1, *c2, *c3]),
+            },
+            (b'c', [o0, o1, o2, o3, chunk @ ..])
+                if !chunk.is_empty() && chunk.len() <= UPDATE_CHUNK_LEN =>
+            {
+                let mut data = [0; UPDATE_CHUNK_LEN];
+                data[..chunk.len()].copy_from_slice(chunk);
+
+                ExtCommand::UpdateChunk {
+                    offset: u32::from_be_bytes([*o0, *o1, *o2, *o3]),
+                    len: chunk.len() as u8,
+                    data,
+                }
+            },
+            (b'F', []) => ExtCommand::UpdateFinish { signature: None },
+            (b'F', signature) if signature.len() == SIGNATURE_LEN => {
+                let mut bytes = [0; SIGNATURE_LEN];
+                bytes.copy_from_slice(signature);
+
+                ExtCommand::UpdateFinish { signature: Some(bytes) }
+            },
+            (b'A', []) => ExtCommand::UpdateAbort,
+            (b'M', [framed]) => ExtCommand::SetFraming { framed: *framed != 0 },
+            (b'H', [version]) => ExtCommand::Hello { version: *version },
+            _ => return Err(Error::MalformedMessage),
+        };
+
+        Ok(command)
+    }
+}
+
+impl ExtReport {
+    pub fn as_arrayvec(&self) -> ArrayVec<[u8; MAX_EXT_FRAME_LEN]> {
+        let mut buf = ArrayVec::new();
+        buf.push(EXT_FRAME_MARKER);
+
+        match self {
+            ExtReport::VolumeLevel { level } => {
+                buf.push(b'v');
+                buf.push(2);
+                buf.try_extend_from_slice(&level.to_be_bytes()).unwrap();
+            },
+            ExtReport::DialValue { dial, diff } => {
+                buf.push(b'd');
+                buf.push(2);
+                buf.push(*dial);
+                buf.push(*diff as u8);
+            },
+            ExtReport::PressTurn { diff } => {
+                buf.push(b't');
+                buf.push(1);
+                buf.push(*diff as u8);
+            },
+            ExtReport::PressReleased { press_turn } => {
+                buf.push(b'p');
+                buf.push(1);
+                buf.push(*press_turn as u8);
+            },
+            ExtReport::ButtonPress { button } => {
+                buf.push(b'P');
+                buf.push(1);
+                buf.push(*button);
+            },
+            ExtReport::ButtonRelease { button } => {
+                buf.push(b'R');
+                buf.push(1);
+                buf.push(*button);
+            },
+            ExtReport::Gesture { button, gesture } => {
+                buf.push(b'G');
+                buf.push(2);
+                buf.push(*button);
+                buf.push(gesture.as_u8());
+            },
+            ExtReport::FailsafeTriggered { reason } => {
+                buf.push(b'f');
+                buf.push(1);
+                buf.push(reason.as_u8());
+            },
+            ExtReport::Startup { reset_flags, boot_reason } => {
+                buf.push(b'S');
+                buf.push(2);
+                buf.push(*reset_flags);
+                buf.push(boot_reason.as_u32() as u8);
+            },
+            ExtReport::UpdateStatus { error, written } => {
+                buf.push(b'u');
+                buf.push(5);
+                buf.push(error.map_or(0, |e| e.as_u8()));
+                buf.try_extend_from_slice(&written.to_be_bytes()).unwrap();
+            },
+            ExtReport::FirmwareSlot { slot, on_trial, rolled_back } => {
+                buf.push(b'F');
+                buf.push(3);
+                buf.push(slot.as_u8());
+                buf.push(*on_trial as u8);
+                buf.push(*rolled_back as u8);
+            },
+            ExtReport::Ack { seq } => {
+                buf.push(b'k');
+                buf.push(1);
+                buf.push(*seq);
+            },
+            ExtReport::Nack { seq, error } => {
+                buf.push(b'n');
+                buf.push(2);
+                buf.push(*seq);
+                buf.push(error.as_u8());
+            },
+            ExtReport::Hello { version } => {
+                buf.push(b'H');
+                buf.push(0); // The payload length, filled in below.
+                buf.push(*version);
+                buf.push(MIN_PROTOCOL_VERSION);
+                buf.push(PROTOCOL_VERSION);
+                buf.push(env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0));
+                buf.push(env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0));
+                buf.push(env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0));
+
+                // The media keys give way to MIDI, along with their settings.
+                let commands = SUPPORTED_COMMANDS
+                    .iter()
+                    .filter(|&&kind| !(cfg!(feature = "midi") && kind == b'C'));
+                for &kind in commands {
+                    buf.push(kind);
+                }
+
+                buf[2] = (buf.len() - EXT_HEADER_LEN) as u8;
+            },
+        }
+
+        buf
+    }
+}
+
+/// The version a stream speaks after a host's `ExtCommand::Hello`, 0 if there's none
+/// in common.
+pub fn negotiate_version(host_version: u8) -> u8 {
+    if host_version < MIN_PROTOCOL_VERSION {
+        0
+    } else {
+        host_version.min(PROTOCOL_VERSION)
+    }
+}
+
+/// Accumulates the bytes of extension frames, which may be split across reads.
+pub struct ExtCommandReader {
+    buf: ArrayVec<[u8; MAX_EXT_FRAME_LEN]>,
+    /// The payload bytes left of a frame too long to be a command, which are skipped.
+    skip: usize,
+}
+
+impl ExtCommandReader {
+    pub fn new() -> Self {
+        Self { buf: ArrayVec::new(), skip: 0 }
+    }
+
+    /// True if the reader is not in the middle of a frame.
+    pub fn is_idle(&self) -> bool {
+        self.buf.is_empty() && self.skip == 0
+    }
+
+    /// Consumes bytes up to the end of the current frame. Returns the number of bytes
+    /// consumed and, once the frame is complete, its command or why it was rejected.
+    pub fn process_bytes(&mut self, bytes: &[u8]) -> (usize, Option<Result<ExtCommand, Error>>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if self.skip > 0 {
+                self.skip -= 1;
+                if self.skip == 0 {
+                    return (i + 1, Some(Err(Error::MalformedMessage)));
+                }
+                continue;
+            }
+
+            if self.buf.is_empty() && byte != EXT_FRAME_MARKER {
+                return (i + 1, Some(Err(Error::MalformedMessage)));
+            }
+
+            if self.buf.try_push(byte).is_err() {
+                self.buf.clear();
+                return (i + 1, Some(Err(Error::BufferFull)));
+            }
+
+            if self.buf.len() >= EXT_HEADER_LEN {
+                let payload_len = self.buf[2] as usize;
+
+                // Skip to the end of the frame, so whatever follows it is read properly.
+                if payload_len > MAX_EXT_PAYLOAD_LEN {
+                    self.buf.clear();
+                    self.skip = payload_len;
+                    continue;
+                }
+
+                if self.buf.len() == EXT_HEADER_LEN + payload_len {
+                    let result = ExtCommand::parse(self.buf[1], &self.buf[EXT_HEADER_LEN..]);
+                    self.buf.clear();
+                    return (i + 1, Some(result));
+                }
+            }
+        }
+
+        (bytes.len(), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `bytes`, which have to be exactly one extension frame.
+    fn parse_frame(bytes: &[u8]) -> Result<ExtCommand, Error> {
+        match ExtCommandReader::new().process_bytes(bytes) {
+            (consumed, Some(result)) if consumed == bytes.len() => result,
+            _ => panic!("not a single frame"),
+        }
+    }
+
+    fn assert_payload_len(report: ExtReport) {
+        let frame = report.as_arrayvec();
+        assert_eq!(frame[0], EXT_FRAME_MARKER);
+        assert_eq!(frame[2] as usize, frame.len() - EXT_HEADER_LEN, "{:?}", report);
+    }
+
+    #[test]
+    fn parse_volume_commands() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'V', 1, 1]).unwrap(),
+            ExtCommand::SetVolumeMode { enabled: true }
+        );
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'v', 2, 0x12, 0x34]).unwrap(),
+            ExtCommand::SetVolumeLevel { level: 0x1234 }
+        );
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'b', 6, 0, 0, 0x03, 0xE8, 0, 10]).unwrap(),
+            ExtCommand::SetVolumeBounds { min: 0, max: 1000, step: 10 }
+        );
+    }
+
+    #[test]
+    fn parse_dial_acceleration() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'a', 4, 1, 2, 8, 1]).unwrap(),
+            ExtCommand::SetDialAcceleration {
+                dial: 1,
+                curve: AccelerationCurve::Exponential,
+                max_multiplier: 8,
+                report_accelerated: true,
+            }
+        );
+        // There's no fourth curve.
+        assert!(matches!(ExtCommand::parse(b'a', &[0, 3, 1, 0]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_dial_resolution() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'r', 3, 0, 4, 1]).unwrap(),
+            ExtCommand::SetDialResolution {
+                dial: 0,
+                counts_per_detent: 4,
+                overflow: DetentOverflow::Chunk,
+            }
+        );
+        assert!(matches!(ExtCommand::parse(b'r', &[0, 4, 2]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_encoder_config() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'e', 4, 1, 2, 5, 0b10]).unwrap(),
+            ExtCommand::SetEncoderConfig {
+                dial: 1,
+                config: EncoderConfig {
+                    mode: EncoderMode::BothEdges,
+                    filter: 5,
+                    invert_a: false,
+                    invert_b: true,
+                },
+            }
+        );
+        assert!(matches!(ExtCommand::parse(b'e', &[0, 3, 0, 0]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_gesture_timing() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'g', 7, 2, 0x01, 0x2C, 0x02, 0x58, 0, 0]).unwrap(),
+            ExtCommand::SetGestureTiming {
+                button: 2,
+                timing: GestureTiming {
+                    multi_click_ms: 300,
+                    long_press_ms: 600,
+                    long_press_repeat_ms: 0,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn parse_debounce_time() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'D', 3, 1, 0, 25]).unwrap(),
+            ExtCommand::SetDebounceTime { button: 1, debounce_time_ms: 25 }
+        );
+    }
+
+    #[test]
+    fn parse_suspend_behavior() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b's', 2, 1, 1]).unwrap(),
+            ExtCommand::SetSuspendBehavior {
+                behavior: SuspendBehavior { lights: SuspendLights::Dim, wake_on_button: true },
+            }
+        );
+        assert!(matches!(ExtCommand::parse(b's', &[3, 0]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_failsafe_commands() {
+        assert_eq!(parse_frame(&[EXT_FRAME_MARKER, b'h', 0]).unwrap(), ExtCommand::Heartbeat);
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'f', 8, 1, 0x13, 0x88, 0, 100, 255, 0, 0]).unwrap(),
+            ExtCommand::SetFailsafe {
+                config: FailsafeConfig {
+                    enabled: true,
+                    timeout_ms: 5000,
+                    scene: FailsafeScene { brightness: 100, led_r: 255, led_g: 0, led_b: 0 },
+                },
+            }
+        );
+        assert!(matches!(ExtCommand::parse(b'h', &[0]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_watchdog_timeout() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'w', 2, 0x07, 0xD0]).unwrap(),
+            ExtCommand::SetWatchdogTimeout { timeout_ms: 2000 }
+        );
+    }
+
+    #[test]
+    fn parse_update_commands() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'U', 8, 0, 1, 0, 0, 0xCB, 0xF4, 0x39, 0x26]).unwrap(),
+            ExtCommand::UpdateBegin { size: 0x10000, crc: 0xCBF4_3926 }
+        );
+
+        let mut data = [0; UPDATE_CHUNK_LEN];
+        data[..2].copy_from_slice(&[0xAA, 0xBB]);
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'c', 6, 0, 0, 1, 0, 0xAA, 0xBB]).unwrap(),
+            ExtCommand::UpdateChunk { offset: 256, len: 2, data }
+        );
+
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'F', 0]).unwrap(),
+            ExtCommand::UpdateFinish { signature: None }
+        );
+        assert_eq!(parse_frame(&[EXT_FRAME_MARKER, b'A', 0]).unwrap(), ExtCommand::UpdateAbort);
+
+        // A chunk needs data, and no more than fits.
+        assert!(matches!(ExtCommand::parse(b'c', &[0, 0, 0, 0]), Err(Error::MalformedMessage)));
+        let too_long = [0; 4 + UPDATE_CHUNK_LEN + 1];
+        assert!(matches!(ExtCommand::parse(b'c', &too_long), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_signatures() {
+        let mut frame = [0xA5; EXT_HEADER_LEN + SIGNATURE_LEN];
+        frame[..EXT_HEADER_LEN].copy_from_slice(&[EXT_FRAME_MARKER, b'F', SIGNATURE_LEN as u8]);
+        assert_eq!(
+            parse_frame(&frame).unwrap(),
+            ExtCommand::UpdateFinish { signature: Some([0xA5; SIGNATURE_LEN]) }
+        );
+
+        // A truncated signature isn't mistaken for a missing one.
+        let truncated = [0xA5; SIGNATURE_LEN - 1];
+        assert!(matches!(ExtCommand::parse(b'F', &truncated), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_consumer_control() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'C', 2, 1, 1]).unwrap(),
+            ExtCommand::SetConsumerControl {
+                config: ConsumerControlConfig {
+                    mode: ConsumerControlMode::WithoutHost,
+                    click: ClickAction::PlayPause,
+                },
+            }
+        );
+        assert!(matches!(ExtCommand::parse(b'C', &[3, 0]), Err(Error::MalformedMessage)));
+        assert!(matches!(ExtCommand::parse(b'C', &[0, 2]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn parse_set_framing() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'M', 1, 1]).unwrap(),
+            ExtCommand::SetFraming { framed: true }
+        );
+    }
+
+    #[test]
+    fn parse_hello() {
+        assert_eq!(
+            parse_frame(&[EXT_FRAME_MARKER, b'H', 1, 7]).unwrap(),
+            ExtCommand::Hello { version: 7 }
+        );
+    }
+
+    #[test]
+    fn every_supported_kind_parses() {
+        for &kind in SUPPORTED_COMMANDS {
+            let parsed = (0..=MAX_EXT_PAYLOAD_LEN)
+                .any(|len| ExtCommand::parse(kind, &[0; MAX_EXT_PAYLOAD_LEN][..len]).is_ok());
+            assert!(parsed, "kind {:?} is never parsed", kind as char);
+        }
+    }
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate_version(0), 0);
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION), MIN_PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(u8::MAX), PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        // An unknown kind.
+        assert!(matches!(ExtCommand::parse(b'?', &[]), Err(Error::MalformedMessage)));
+        // A payload of the wrong length.
+        assert!(matches!(ExtCommand::parse(b'v', &[1]), Err(Error::MalformedMessage)));
+        assert!(matches!(ExtCommand::parse(b'V', &[]), Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let mut reader = ExtCommandReader::new();
+        assert!(matches!(reader.process_bytes(&[EXT_FRAME_MARKER, b'v']), (2, None)));
+        assert!(!reader.is_idle());
+
+        // Only the bytes of the frame are consumed.
+        let (consumed, command) = reader.process_bytes(&[2, 0, 7, EXT_FRAME_MARKER]);
+        assert_eq!(consumed, 3);
+        assert_eq!(command.unwrap().unwrap(), ExtCommand::SetVolumeLevel { level: 7 });
+        assert!(reader.is_idle());
+    }
+
+    #[test]
+    fn overlong_frames_are_skipped() {
+        let mut reader = ExtCommandReader::new();
+        let payload_len = MAX_EXT_PAYLOAD_LEN + 1;
+
+        assert!(matches!(
+            reader.process_bytes(&[EXT_FRAME_MARKER, b'v', payload_len as u8]),
+            (3, None)
+        ));
+
+        // The payload may look like frames of its own, but it's skipped.
+        let payload = [EXT_FRAME_MARKER; MAX_EXT_PAYLOAD_LEN + 1];
+        let (consumed, command) = reader.process_bytes(&payload[..payload_len - 1]);
+        assert_eq!(consumed, payload_len - 1);
+        assert!(command.is_none());
+
+        let (consumed, command) = reader.process_bytes(&[EXT_FRAME_MARKER, EXT_FRAME_MARKER]);
+        assert_eq!(consumed, 1);
+        assert!(matches!(command, Some(Err(Error::MalformedMessage))));
+        assert!(reader.is_idle());
+    }
+
+    #[test]
+    fn frames_must_start_with_the_marker() {
+        let (consumed, command) = ExtCommandReader::new().process_bytes(&[0x00, 0x01]);
+        assert_eq!(consumed, 1);
+        assert!(matches!(command, Some(Err(Error::MalformedMessage))));
+    }
+
+    #[test]
+    fn volume_reports() {
+        assert_payload_len(ExtReport::VolumeLevel { level: 100 });
+    }
+
+    #[test]
+    fn update_reports() {
+        assert_payload_len(ExtReport::UpdateStatus { error: None, written: 1024 });
+        assert_payload_len(ExtReport::UpdateStatus {
+            error: Some(UpdateError::CrcMismatch),
+            written: 0,
+        });
+    }
+
+    #[test]
+    fn firmware_slot_reports() {
+        assert_payload_len(ExtReport::FirmwareSlot {
+            slot: SlotId::B,
+            on_trial: true,
+            rolled_back: false,
+        });
+    }
+
+    #[test]
+    fn framing_reports() {
+        assert_payload_len(ExtReport::Ack { seq: 5 });
+        assert_payload_len(ExtReport::Nack { seq: 5, error: FrameError::Busy });
+    }
+
+    #[test]
+    fn hello_reports() {
+        let frame = ExtReport::Hello { version: PROTOCOL_VERSION }.as_arrayvec();
+        assert_payload_len(ExtReport::Hello { version: PROTOCOL_VERSION });
+        assert_eq!(frame[3..6], [PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION]);
+        assert!(frame.ends_with(b"AMH"));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::clock::Instant;
+
+/// The state the lights switch to when the host is lost.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FailsafeScene {
+    /// Brightness of both overhead lights.
+    pub brightness: u16,
+
+    /// Solid color of the LED ring.
+    pub led_r: u8,
+    pub led_g: u8,
+    pub led_b: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FailsafeConfig {
+    /// The failsafe does nothing until the host enables it.
+    pub enabled: bool,
+
+    /// How long the host may go without sending any command (a heartbeat will do).
+    /// 0 disables the timeout, so only closing the serial port triggers the failsafe.
+    pub timeout_ms: u16,
+
+    pub scene: FailsafeScene,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailsafeReason {
+    Timeout,
+    HostDisconnected,
+}
+
+impl FailsafeReason {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            FailsafeReason::Timeout => 0,
+            FailsafeReason::HostDisconnected => 1,
+        }
+    }
+}
+
+/// Watches for signs of life from the host, so the panel doesn't get stuck in whatever
+/// state a crashed host daemon left it in.
+pub struct HostWatchdog {
+    config: FailsafeConfig,
+    last_seen: Instant,
+    was_connected: bool,
+    /// Why the failsafe was triggered, until the host is told about it.
+    triggered: Option<FailsafeReason>,
+}
+
+impl HostWatchdog {
+    pub fn new(now: Instant) -> Self {
+        let config = FailsafeConfig {
+            enabled: false,
+            timeout_ms: 0,
+            scene: FailsafeScene { brightness: 0, led_r: 0, led_g: 0, led_b: 0 },
+        };
+
+        Self { config, last_seen: now, was_connected: false, triggered: None }
+    }
+
+    pub fn configure(&mut self, config: FailsafeConfig, now: Instant) {
+        self.config = config;
+        self.last_seen = now;
+    }
+
+    /// Records that the host is alive.
+    pub fn feed(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+
+    /// Returns the scene to switch to if the host has just been lost.
+    pub fn poll(&mut self, host_connected: bool, now: Instant) -> Option<FailsafeScene> {
+        let disconnected = self.was_connected && !host_connected;
+        // A host which has just connected hasn't had the chance to send anything yet.
+        if host_connected && !self.was_connected {
+            self.last_seen = now;
+        }
+        self.was_connected = host_connected;
+
+        if !self.config.enabled || self.triggered.is_some() {
+            return None;
+        }
+
+        let timeout_ms = self.config.timeout_ms as u32;
+        let reason = if disconnected {
+            FailsafeReason::HostDisconnected
+        } else if timeout_ms > 0 && now.has_elapsed(self.last_seen, timeout_ms) {
+            FailsafeReason::Timeout
+        } else {
+            return None;
+        };
+
+        self.triggered = Some(reason);
+        Some(self.config.scene)
+    }
+
+    /// Takes the reason the failsafe was triggered, once the host is back to hear it.
+    /// The timeout starts over, so the failsafe doesn't trigger again right away.
+    pub fn take_triggered(&mut self, now: Instant) -> Option<FailsafeReason> {
+        let reason = self.triggered.take();
+        if reason.is_some() {
+            self.last_seen = now;
+        }
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: FailsafeScene = FailsafeScene { brightness: 10, led_r: 255, led_g: 0, led_b: 0 };
+
+    fn watchdog(timeout_ms: u16) -> HostWatchdog {
+        let mut watchdog = HostWatchdog::new(Instant::from_ms(0));
+        let config = FailsafeConfig { enabled: true, timeout_ms, scene: SCENE };
+        watchdog.configure(config, Instant::from_ms(0));
+        watchdog
+    }
+
+    #[test]
+    fn triggers_once_on_timeout() {
+        let mut watchdog = watchdog(1000);
+
+        assert_eq!(watchdog.poll(true, Instant::from_ms(999)), None);
+        assert_eq!(watchdog.poll(true, Instant::from_ms(1000)), Some(SCENE));
+        assert_eq!(watchdog.poll(true, Instant::from_ms(5000)), None);
+        assert_eq!(watchdog.take_triggered(Instant::from_ms(5000)), Some(FailsafeReason::Timeout));
+        assert_eq!(watchdog.take_triggered(Instant::from_ms(5000)), None);
+    }
+
+    #[test]
+    fn triggers_on_disconnect() {
+        let mut watchdog = watchdog(0);
+
+        assert_eq!(watchdog.poll(true, Instant::from_ms(0)), None);
+        assert_eq!(watchdog.poll(true, Instant::from_ms(100_000)), None);
+        assert_eq!(watchdog.poll(false, Instant::from_ms(100_001)), Some(SCENE));
+        assert_eq!(
+            watchdog.take_triggered(Instant::from_ms(100_002)),
+            Some(FailsafeReason::HostDisconnected)
+        );
+    }
+
+    #[test]
+    fn returning_hosts_get_a_fresh_timeout() {
+        let mut watchdog = watchdog(1000);
+
+        assert_eq!(watchdog.poll(true, Instant::from_ms(0)), None);
+        assert_eq!(watchdog.poll(false, Instant::from_ms(10)), Some(SCENE));
+
+        // The host comes back long after, and is told before it sends anything.
+        assert_eq!(watchdog.poll(true, Instant::from_ms(60_000)), None);
+        assert!(watchdog.take_triggered(Instant::from_ms(60_000)).is_some());
+        assert_eq!(watchdog.poll(true, Instant::from_ms(60_010)), None);
+        assert_eq!(watchdog.poll(true, Instant::from_ms(61_000)), Some(SCENE));
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let mut watchdog = HostWatchdog::new(Instant::from_ms(0));
+
+        assert_eq!(watchdog.poll(true, Instant::from_ms(0)), None);
+        assert_eq!(watchdog.poll(false, Instant::from_ms(100_000)), None);
+    }
+}
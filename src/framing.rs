@@ -0,0 +1,270 @@
+use crate::crc::Crc16;
+use panel_protocol::ArrayVec;
+
+// The framed mode, for hosts which need to know that every command arrived intact.
+// `ExtCommand::SetFraming` switches it on and off. In framed mode every message in either
+// direction, be it a panel-protocol command or report or an extension frame, is sent as
+//
+//   COBS([seq, message..., crc_hi, crc_lo]), FRAME_DELIMITER
+//
+// where the CRC-16 covers the sequence number and the message. The firmware answers each
+// command frame with `ExtReport::Ack` or `ExtReport::Nack`, carrying its sequence number.
+// A command repeating the previous sequence number is acknowledged again without being
+// run twice, so the host can send one frame at a time and resend it until it's acked.
+// Reports are numbered by the firmware, so the host can tell when it missed some.
+pub const FRAME_DELIMITER: u8 = 0x00;
+
+const SEQ_LEN: usize = 1;
+const CRC_LEN: usize = 2;
+// Enough for any command or report.
+const MAX_MESSAGE_LEN: usize = 128;
+const MAX_FRAME_LEN: usize = SEQ_LEN + MAX_MESSAGE_LEN + CRC_LEN;
+// COBS adds a byte for every 254, plus one, and there's the delimiter.
+pub const MAX_ENCODED_FRAME_LEN: usize = MAX_FRAME_LEN + MAX_FRAME_LEN / 254 + 2;
+
+/// Why a command frame was rejected, sent with `ExtReport::Nack`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError {
+    /// The frame isn't valid COBS, or is too long.
+    Framing,
+
+    /// The CRC doesn't match, the frame was corrupted on the way.
+    Crc,
+
+    /// The message isn't exactly one valid command.
+    Malformed,
+
+    /// Too many commands are waiting to be handled, resend it later.
+    Busy,
+}
+
+impl FrameError {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            FrameError::Framing => 1,
+            FrameError::Crc => 2,
+            FrameError::Malformed => 3,
+            FrameError::Busy => 4,
+        }
+    }
+}
+
+pub struct Frame {
+    pub seq: u8,
+    pub message: ArrayVec<[u8; MAX_MESSAGE_LEN]>,
+}
+
+/// Accumulates the bytes of a frame up to its delimiter, as it may be split across reads.
+pub struct FrameReader {
+    buf: ArrayVec<[u8; MAX_ENCODED_FRAME_LEN]>,
+    overflowed: bool,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self { buf: ArrayVec::new(), overflowed: false }
+    }
+
+    /// Consumes bytes up to the end of the current frame. Returns the number of bytes
+    /// consumed and the frame, or the sequence number and error of a broken one, once
+    /// the delimiter arrives. The sequence number of a broken frame is only a guess.
+    pub fn process_bytes(
+        &mut self,
+        bytes: &[u8],
+    ) -> (usize, Option<Result<Frame, (u8, FrameError)>>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if byte != FRAME_DELIMITER {
+                if self.buf.try_push(byte).is_err() {
+                    self.overflowed = true;
+                }
+                continue;
+            }
+
+            // Back to back delimiters are harmless, a host may send one to flush out noise.
+            if self.buf.is_empty() && !self.overflowed {
+                continue;
+            }
+
+            let result =
+                if self.overflowed { Err((0, FrameError::Framing)) } else { self.decode() };
+            self.buf.clear();
+            self.overflowed = false;
+
+            return (i + 1, Some(result));
+        }
+
+        (bytes.len(), None)
+    }
+
+    fn decode(&self) -> Result<Frame, (u8, FrameError)> {
+        let frame = cobs_decode(&self.buf).ok_or((0, FrameError::Framing))?;
+        if frame.len() < SEQ_LEN + CRC_LEN {
+            return Err((0, FrameError::Framing));
+        }
+
+        let seq = frame[0];
+        let (contents, crc) = frame.split_at(frame.len() - CRC_LEN);
+
+        let mut expected_crc = Crc16::new();
+        expected_crc.update(contents);
+        if expected_crc.finish().to_be_bytes() != crc {
+            return Err((seq, FrameError::Crc));
+        }
+
+        let mut message = ArrayVec::new();
+        message
+            .try_extend_from_slice(&contents[SEQ_LEN..])
+            .map_err(|_| (seq, FrameError::Framing))?;
+
+        Ok(Frame { seq, message })
+    }
+}
+
+/// Frames `message`, including the delimiter. Returns nothing if the message is too long
+/// for a frame, which no command or report is.
+pub fn encode(seq: u8, message: &[u8]) -> ArrayVec<[u8; MAX_ENCODED_FRAME_LEN]> {
+    let mut frame: ArrayVec<[u8; MAX_FRAME_LEN]> = ArrayVec::new();
+    frame.push(seq);
+    if frame.try_extend_from_slice(message).is_err() {
+        return ArrayVec::new();
+    }
+
+    let mut crc = Crc16::new();
+    crc.update(&frame);
+    let _ = frame.try_extend_from_slice(&crc.finish().to_be_bytes());
+
+    let mut encoded = cobs_encode(&frame);
+    encoded.push(FRAME_DELIMITER);
+    encoded
+}
+
+// Consistent Overhead Byte Stuffing: every run of up to 254 non-zero bytes is prefixed with
+// its length + 1, which stands for a zero after the run unless the length is 254.
+fn cobs_encode(data: &[u8]) -> ArrayVec<[u8; MAX_ENCODED_FRAME_LEN]> {
+    let mut encoded = ArrayVec::new();
+    let mut code_index = 0;
+    let mut code = 1;
+    encoded.push(0);
+
+    for &byte in data {
+        if byte != 0 {
+            encoded.push(byte);
+            code += 1;
+        }
+
+        if byte == 0 || code == 0xFF {
+            encoded[code_index] = code;
+            code_index = encoded.len();
+            encoded.push(0);
+            code = 1;
+        }
+    }
+
+    encoded[code_index] = code;
+    encoded
+}
+
+fn cobs_decode(encoded: &[u8]) -> Option<ArrayVec<[u8; MAX_FRAME_LEN]>> {
+    let mut data = ArrayVec::new();
+    let mut i = 0;
+
+    while i < encoded.len() {
+        let code = encoded[i] as usize;
+        let run = encoded.get(i + 1..i + code)?;
+        data.try_extend_from_slice(run).ok()?;
+        i += code;
+
+        if code < 0xFF && i < encoded.len() {
+            data.try_push(0).ok()?;
+        }
+    }
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_cobs_round_trip(data: &[u8]) {
+        let encoded = cobs_encode(data);
+        assert!(!encoded.contains(&0), "{:?} encoded to {:?}", data, encoded);
+        assert_eq!(cobs_decode(&encoded).unwrap()[..], *data);
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        assert_cobs_round_trip(&[]);
+        assert_cobs_round_trip(&[0]);
+        assert_cobs_round_trip(&[0, 0]);
+        assert_cobs_round_trip(&[1, 0, 2]);
+        assert_cobs_round_trip(&[0, 1, 2, 0]);
+        assert_cobs_round_trip(&[0xFF; MAX_FRAME_LEN]);
+    }
+
+    #[test]
+    fn cobs_encoding() {
+        assert_eq!(cobs_encode(&[])[..], [1]);
+        assert_eq!(cobs_encode(&[0])[..], [1, 1]);
+        assert_eq!(cobs_encode(&[0x11, 0x22, 0x00, 0x33])[..], [3, 0x11, 0x22, 2, 0x33]);
+    }
+
+    #[test]
+    fn cobs_rejects_truncated_runs() {
+        assert!(cobs_decode(&[3, 0x11]).is_none());
+        assert!(cobs_decode(&[0]).is_none());
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let message = [0xFE, b'h', 0];
+        let encoded = encode(42, &message);
+        assert_eq!(encoded.last(), Some(&FRAME_DELIMITER));
+
+        let mut reader = FrameReader::new();
+        let (consumed, frame) = reader.process_bytes(&encoded);
+        let frame = frame.unwrap().unwrap();
+        assert_eq!(consumed, encoded.len());
+        assert_eq!(frame.seq, 42);
+        assert_eq!(frame.message[..], message);
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let encoded = encode(1, b"hello");
+        let (first, second) = encoded.split_at(3);
+
+        let mut reader = FrameReader::new();
+        assert!(matches!(reader.process_bytes(first), (3, None)));
+        let (_, frame) = reader.process_bytes(second);
+        assert_eq!(frame.unwrap().unwrap().message[..], *b"hello");
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        let mut encoded = encode(9, b"hello");
+        encoded[3] ^= 0x20;
+
+        let (_, frame) = FrameReader::new().process_bytes(&encoded);
+        assert!(matches!(frame, Some(Err((9, FrameError::Crc)))));
+    }
+
+    #[test]
+    fn overlong_frames_are_rejected() {
+        let mut reader = FrameReader::new();
+        assert!(matches!(reader.process_bytes(&[0xFF; MAX_ENCODED_FRAME_LEN + 1]), (_, None)));
+        assert!(matches!(
+            reader.process_bytes(&[FRAME_DELIMITER]),
+            (1, Some(Err((0, FrameError::Framing))))
+        ));
+
+        // The reader starts over after the delimiter.
+        let (_, frame) = reader.process_bytes(&encode(2, b"ok"));
+        assert!(matches!(frame, Some(Ok(Frame { seq: 2, .. }))));
+    }
+
+    #[test]
+    fn messages_too_long_for_a_frame_are_not_encoded() {
+        assert!(encode(0, &[1; MAX_MESSAGE_LEN + 1]).is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    button::{Button, ButtonEvent, Gesture, GestureRecognizer, GestureTiming},
+    clock::Instant,
+};
+use core::convert::Infallible;
+use embedded_hal::digital::v2::InputPin;
+use panel_protocol::ArrayVec;
+
+pub const MAX_BUTTONS: usize = 8;
+// Each button can produce at most one button event and one gesture per poll.
+const MAX_INPUT_EVENTS: usize = 2 * MAX_BUTTONS;
+
+pub type ButtonId = u8;
+
+/// A debounced button, regardless of which pin it's connected to.
+pub trait PolledButton {
+    fn poll(&mut self, now: Instant) -> Option<ButtonEvent>;
+
+    fn is_pressed(&self) -> bool;
+
+    fn set_debounce_time_ms(&mut self, debounce_time_ms: u16);
+}
+
+impl<T: InputPin<Error = Infallible>> PolledButton for Button<T> {
+    fn poll(&mut self, now: Instant) -> Option<ButtonEvent> {
+        Button::poll(self, now)
+    }
+
+    fn is_pressed(&self) -> bool {
+        Button::is_pressed(self)
+    }
+
+    fn set_debounce_time_ms(&mut self, debounce_time_ms: u16) {
+        Button::set_debounce_time_ms(self, debounce_time_ms)
+    }
+}
+
+pub enum InputEvent {
+    Button { button: ButtonId, event: ButtonEvent },
+    Gesture { button: ButtonId, gesture: Gesture },
+}
+
+struct Input<'a> {
+    id: ButtonId,
+    button: &'a mut dyn PolledButton,
+    gestures: GestureRecognizer,
+}
+
+/// All of the panel's buttons, each tagged with an ID which is used in reports
+/// and commands to tell them apart.
+pub struct Inputs<'a> {
+    inputs: ArrayVec<[Input<'a>; MAX_BUTTONS]>,
+}
+
+impl<'a> Inputs<'a> {
+    pub fn new() -> Self {
+        Self { inputs: ArrayVec::new() }
+    }
+
+    /// Adds a button with the given ID. Panics if more than `MAX_BUTTONS` are added.
+    pub fn add(&mut self, id: ButtonId, button: &'a mut dyn PolledButton) {
+        let gestures = GestureRecognizer::new(GestureTiming::default());
+        self.inputs.push(Input { id, button, gestures });
+    }
+
+    pub fn poll(&mut self, now: Instant) -> ArrayVec<[InputEvent; MAX_INPUT_EVENTS]> {
+        let mut events = ArrayVec::new();
+
+        for input in self.inputs.iter_mut() {
+            let event = input.button.poll(now);
+
+            if let Some(gesture) = input.gestures.update(event.as_ref(), now) {
+                events.push(InputEvent::Gesture { button: input.id, gesture });
+            }
+
+            if let Some(event) = event {
+                events.push(InputEvent::Button { button: input.id, event });
+            }
+        }
+
+        events
+    }
+
+    pub fn is_pressed(&self, id: ButtonId) -> bool {
+        self.inputs.iter().any(|input| input.id == id && input.button.is_pressed())
+    }
+
+    /// Stops the current press of a button from being recognized as a gesture.
+    pub fn cancel_gesture(&mut self, id: ButtonId) {
+        if let Some(input) = self.get_mut(id) {
+            input.gestures.cancel();
+        }
+    }
+
+    pub fn set_gesture_timing(&mut self, id: ButtonId, timing: GestureTiming) {
+        if let Some(input) = self.get_mut(id) {
+            input.gestures.set_timing(timing);
+        }
+    }
+
+    pub fn set_debounce_time_ms(&mut self, id: ButtonId, debounce_time_ms: u16) {
+        if let Some(input) = self.get_mut(id) {
+            input.button.set_debounce_time_ms(debounce_time_ms);
+        }
+    }
+
+    fn get_mut(&mut self, id: ButtonId) -> Option<&mut Input<'a>> {
+        self.inputs.iter_mut().find(|input| input.id == id)
+    }
+}
@@ -1,21 +1,43 @@
-#![no_main]
-#![no_std]
+// Unit tests run on the host, with `make test`.
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+// Most of the firmware is only reachable from `main()`, which tests don't run.
+#![cfg_attr(test, allow(dead_code))]
 
 use crate::{rgb::Rgb, rgb_led::LED_COUNT};
-use panel_protocol::PulseMode;
-use panic_reset as _; // panic handler
+use core::fmt::Write;
+use panel_protocol::{ArrayString, ArrayVec, PulseMode};
 
 use stm32f4xx_hal as hal;
 
+#[cfg(not(feature = "midi"))]
+use crate::consumer_control::ConsumerControl;
+#[cfg(feature = "midi")]
+use crate::midi::Midi;
+#[cfg(feature = "uart")]
+use crate::uart::UartTransport;
 use crate::{
+    backup_domain::BackupDomain,
     button::{Active, Button, ButtonEvent, Debouncer},
+    clock::Clock,
     counter::Counter,
+    dfu::DfuRuntime,
+    extension::{ExtCommand, ExtReport},
+    failsafe::HostWatchdog,
+    input::{ButtonId, InputEvent, Inputs},
     overhead_light::OverheadLight,
+    power::{SuspendBehavior, SuspendLights},
+    reset_cause::StartupInfo,
     rgb_led::{LedStrip, Pulser},
-    serial::{Command, Report, SerialProtocol},
+    serial::{Command, Incoming, Report, SerialProtocol},
+    update::{FirmwareUpdate, SlotTrial},
+    vendor::VendorInterface,
+    volume::Volume,
+    watchdog::Watchdog,
 };
-use cortex_m_rt::entry;
 use embedded_hal::digital::v2::OutputPin;
+#[cfg(feature = "uart")]
+use hal::serial::Serial;
 use hal::{
     otg_fs::{UsbBus, USB},
     prelude::*,
@@ -26,27 +48,82 @@ use hal::{
     timer::MonoTimer,
 };
 use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
-use usbd_serial::{SerialPort, USB_CLASS_CDC};
+#[cfg(not(feature = "uart"))]
+use usbd_serial::SerialPort;
 
+mod backup_domain;
 mod bootload;
 mod button;
+mod clock;
+// Only the settings are used when MIDI takes the HID interface's place.
+#[cfg_attr(feature = "midi", allow(dead_code))]
+mod consumer_control;
 mod counter;
+mod crash; // panic handler
+mod crc;
+mod dfu;
+mod encoder;
+mod extension;
+mod failsafe;
+mod framing;
+mod input;
+#[cfg(feature = "midi")]
+mod midi;
 mod overhead_light;
+mod power;
+mod reset_cause;
 mod rgb;
 mod rgb_led;
 mod serial;
+mod slots;
+mod transport;
+#[cfg(any(feature = "uart", test))]
+mod uart;
+mod update;
+mod vendor;
+mod volume;
+mod wakeup;
+mod watchdog;
 
 static mut USB_ENDPOINT_MEMORY: [u32; 1024] = [0; 1024];
 const FADE_CONSTANT: f32 = 0.994;
 
-#[entry]
+// Device class codes for a composite device using interface association descriptors.
+const USB_CLASS_MISCELLANEOUS: u8 = 0xEF;
+const USB_SUBCLASS_COMMON: u8 = 0x02;
+const USB_PROTOCOL_IAD: u8 = 0x01;
+
+// For panels driven by an embedded host over the UART instead of USB serial.
+#[cfg(feature = "uart")]
+const UART_BAUD_RATE: u32 = 115_200;
+
+// Dial IDs used by the extension commands and reports.
+const VOLUME_DIAL: u8 = 0;
+const BRIGHTNESS_DIAL: u8 = 1;
+
+// Button IDs used by the extension commands and reports.
+const DIAL_BUTTON: ButtonId = 0;
+
+#[cfg_attr(not(test), cortex_m_rt::entry)]
 fn main() -> ! {
     let panel_serial_number = env!("PANEL_SERIAL_NUMBER");
     let cp = cortex_m::peripheral::Peripherals::take().expect("failed to get cortex_m peripherals");
     let dp = stm32::Peripherals::take().expect("failed to get stm32 peripherals");
 
+    let mut backup_domain = BackupDomain::new(dp.RTC, dp.PWR);
+
     // This call needs to happen as early as possible in the firmware.
-    bootload::jump_to_bootloader_if_requested(&dp);
+    bootload::jump_to_bootloader_if_requested(&mut backup_domain);
+
+    // Start the watchdog right after, so a hang anywhere in the setup below (the
+    // clocks, USB, a freshly installed firmware on trial) resets the MCU too. The
+    // timeout covers the whole setup, including the 100ms USB reset below.
+    // It can't start any earlier, as it can't be stopped for the ST bootloader.
+    let mut watchdog = Watchdog::start(dp.IWDG, watchdog::DEFAULT_TIMEOUT_MS);
+
+    // Remember why we booted, so we can tell the host later.
+    let startup_info = reset_cause::take_startup_info(&dp.RCC, &mut backup_domain);
+    let mut crash_record = crash::read_crash_record(&backup_domain);
 
     // Take ownership over the raw devices and convert them into the corresponding
     // HAL structs.
@@ -83,7 +160,13 @@ fn main() -> ! {
 
     let mut led_strip = LedStrip::new(spi);
 
+    // Timeouts and debouncing go by the clock. The cycle counter only drives the breathing,
+    // which keeps the main loop from sleeping, as it stops while the core sleeps.
+    let clock = Clock::start(cp.SYST, &clocks);
     let timer = MonoTimer::new(cp.DWT, cp.DCB, clocks);
+
+    // A freshly installed firmware has to prove itself before it's booted again.
+    let mut slot_trial = SlotTrial::take(&mut backup_domain, clock.now());
     // Human relaxed breath time: around 4s in/out and 4s wait
     let mut pulser = Pulser::new(4000, &timer);
 
@@ -119,11 +202,25 @@ fn main() -> ! {
     let rotary_encoder = Qei::new(rotary_encoder_timer, rotary_encoder_pins);
 
     let mut counter = Counter::new(rotary_encoder);
+    let mut volume = Volume::new();
+
+    // Connect a second rotary encoder, for adjusting brightness, to pins B6 and B7.
+    let brightness_encoder_pins = (gpiob.pb6.into_alternate_af2(), gpiob.pb7.into_alternate_af2());
+    let brightness_encoder = Qei::new(dp.TIM4, brightness_encoder_pins);
+
+    let mut brightness_counter = Counter::new(brightness_encoder);
+
+    // Whether each dial reports raw or accelerated movement, indexed by dial ID.
+    let mut report_accelerated_dial = [false; 2];
 
     let button_pin = gpioa.pa10.into_pull_up_input();
-    let debounced_encoder_pin = Debouncer::new(button_pin, Active::Low, 30, 3000);
+    let debounced_encoder_pin = Debouncer::new(button_pin, Active::Low, 30, clock.now());
     let mut encoder_button = Button::new(debounced_encoder_pin);
 
+    // Additional buttons (mute, call, etc.) can be added here with their own IDs.
+    let mut inputs = Inputs::new();
+    inputs.add(DIAL_BUTTON, &mut encoder_button);
+
     let mut led_color = Rgb::new_from_u8(0, 30, 255);
     let mut led_pulse = PulseMode::Solid;
 
@@ -133,10 +230,13 @@ fn main() -> ! {
     // booting up after a USB DFU firmware update. Without this,
     // the USB serial device sometimes doesn't show on the host OS
     // after booting up.
-    let mut delay = hal::delay::Delay::new(cp.SYST, clocks);
     let mut usb_pin_d_plus = gpioa.pa12.into_push_pull_output();
     usb_pin_d_plus.set_low().unwrap();
-    delay.delay_ms(100_u32);
+    cortex_m::asm::delay(clocks.sysclk().0 / 10);
+
+    // From here on SysTick paces the main loop while it's idle, and input edges
+    // wake it up right away.
+    wakeup::enable_input_wakeup(&dp.SYSCFG, &dp.EXTI);
 
     // Now we can connect as a USB serial device to the host.
     let usb_pin_d_plus = usb_pin_d_plus.into_alternate_af10();
@@ -153,70 +253,354 @@ fn main() -> ! {
     };
 
     let usb_bus = UsbBus::new(usb, unsafe { &mut USB_ENDPOINT_MEMORY });
-    let serial = SerialPort::new(&usb_bus);
+    #[cfg(not(feature = "uart"))]
+    let transport = SerialPort::new(&usb_bus);
+    #[cfg(feature = "uart")]
+    let transport = {
+        // USART1 on A15 (TX) and B3 (RX), the pins the dials and the lights leave free.
+        let pins = (gpioa.pa15.into_alternate_af7(), gpiob.pb3.into_alternate_af7());
+        let config = hal::serial::config::Config::default().baudrate(UART_BAUD_RATE.bps());
+        UartTransport::new(Serial::usart1(dp.USART1, pins, config, clocks).unwrap(), &clock)
+    };
+    let dfu = DfuRuntime::new(&usb_bus);
+    let vendor = VendorInterface::new(&usb_bus);
+    #[cfg(not(feature = "midi"))]
+    let media = ConsumerControl::new(&usb_bus);
+    #[cfg(feature = "midi")]
+    let media = Midi::new(&usb_bus);
 
     let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
         .manufacturer("tonari")
         .product("panel_controller")
         .serial_number(panel_serial_number)
-        .device_class(USB_CLASS_CDC)
+        // A composite device, the CDC interfaces are grouped by an interface association.
+        .device_class(USB_CLASS_MISCELLANEOUS)
+        .device_sub_class(USB_SUBCLASS_COMMON)
+        .device_protocol(USB_PROTOCOL_IAD)
         .build();
 
-    let mut protocol = SerialProtocol::new(usb_dev, serial);
+    let mut protocol = SerialProtocol::new(usb_dev, transport, dfu, media, vendor);
 
     // Turn the LED on to indicate we've powered up successfully.
     led.set_low().unwrap();
 
+    // Set when the dial is turned while the button is held down.
+    let mut turned_while_pressed = false;
+
+    let mut suspend_behavior = SuspendBehavior::default();
+    let mut was_suspended = false;
+    // Set when a button press brings the lights back during a suspend.
+    let mut woken_by_button = false;
+
+    let mut host_watchdog = HostWatchdog::new(clock.now());
+
+    let mut firmware_update = FirmwareUpdate::new(dp.FLASH);
+
     let mut active_led_index = 0usize;
     let mut current_led_colors = [Rgb::new_from_u8(0, 0, 0); LED_COUNT];
     let mut target_led_colors = current_led_colors;
 
     loop {
-        match encoder_button.poll() {
-            Some(ButtonEvent::Press) => {
-                protocol.report(Report::Press).unwrap();
-                led.set_low().unwrap();
+        let now = clock.now();
+
+        for input_event in inputs.poll(now) {
+            if matches!(input_event, InputEvent::Button { event: ButtonEvent::Press, .. })
+                && protocol.is_suspended()
+                && suspend_behavior.wake_on_button
+            {
+                woken_by_button = true;
+            }
+
+            match input_event {
+                InputEvent::Button { button: DIAL_BUTTON, event: ButtonEvent::Press } => {
+                    protocol.report(Report::Press).unwrap();
+                    #[cfg(feature = "midi")]
+                    protocol.midi().button(true);
+                    turned_while_pressed = false;
+                    led.set_low().unwrap();
+                },
+                InputEvent::Button { button: DIAL_BUTTON, event: ButtonEvent::Release } => {
+                    protocol.report(Report::Release).unwrap();
+                    #[cfg(feature = "midi")]
+                    protocol.midi().button(false);
+                    protocol
+                        .report_ext(ExtReport::PressReleased { press_turn: turned_while_pressed })
+                        .unwrap();
+                    led.set_high().unwrap();
+                },
+                InputEvent::Button { button, event: ButtonEvent::Press } => {
+                    protocol.report_ext(ExtReport::ButtonPress { button }).unwrap();
+                },
+                InputEvent::Button { button, event: ButtonEvent::Release } => {
+                    protocol.report_ext(ExtReport::ButtonRelease { button }).unwrap();
+                },
+                InputEvent::Gesture { button, gesture } => {
+                    #[cfg(not(feature = "midi"))]
+                    if button == DIAL_BUTTON && gesture == crate::button::Gesture::Click {
+                        let host_connected = protocol.is_host_connected();
+                        protocol.consumer_control().click(host_connected);
+                    }
+
+                    protocol.report_ext(ExtReport::Gesture { button, gesture }).unwrap();
+                },
+            }
+        }
+
+        if let Some(detents) = counter.poll(now) {
+            if inputs.is_pressed(DIAL_BUTTON) {
+                turned_while_pressed = true;
+                inputs.cancel_gesture(DIAL_BUTTON);
+
+                let diff = if report_accelerated_dial[VOLUME_DIAL as usize] {
+                    detents.accelerated
+                } else {
+                    detents.raw
+                };
+                protocol.report_ext(ExtReport::PressTurn { diff }).unwrap();
+            } else {
+                #[cfg(not(feature = "midi"))]
+                {
+                    let host_connected = protocol.is_host_connected();
+                    protocol.consumer_control().turn(detents.accelerated, host_connected);
+                }
+                #[cfg(feature = "midi")]
+                protocol.midi().dial(VOLUME_DIAL, detents.raw);
+
+                if volume.is_enabled() {
+                    if let Some(level) = volume.apply_diff(detents.accelerated) {
+                        protocol.report_ext(ExtReport::VolumeLevel { level }).unwrap();
+                    }
+                } else {
+                    let diff = if report_accelerated_dial[VOLUME_DIAL as usize] {
+                        detents.accelerated
+                    } else {
+                        detents.raw
+                    };
+                    protocol.report(Report::DialValue { diff }).unwrap();
+                }
+
+                active_led_index = active_led_index.wrapping_add(detents.raw as usize) % LED_COUNT;
+            }
+        }
+
+        if let Some(detents) = brightness_counter.poll(now) {
+            #[cfg(feature = "midi")]
+            protocol.midi().dial(BRIGHTNESS_DIAL, detents.raw);
+
+            let diff = if report_accelerated_dial[BRIGHTNESS_DIAL as usize] {
+                detents.accelerated
+            } else {
+                detents.raw
+            };
+            protocol.report_ext(ExtReport::DialValue { dial: BRIGHTNESS_DIAL, diff }).unwrap();
+        }
+
+        let incoming = match protocol.poll() {
+            Ok(incoming) => incoming,
+            // Overruns and line noise on the UART. Those bytes are lost, but that's no
+            // reason to reset the panel.
+            Err(serial::Error::Serial(_)) => {
+                protocol.debug("UART receive error");
+                ArrayVec::new()
             },
-            Some(ButtonEvent::Release) => {
-                protocol.report(Report::Release).unwrap();
-                led.set_high().unwrap();
+            Err(_) => {
+                protocol.debug("Receive error");
+                ArrayVec::new()
             },
-            _ => {},
+        };
+
+        if protocol.dfu_detach_requested() {
+            // Give the host a moment to see its detach request complete.
+            cortex_m::asm::delay(clocks.sysclk().0 / 100);
+
+            led.set_high().unwrap();
+            bootload::request_bootloader(&mut backup_domain);
         }
 
-        if let Some(diff) = counter.poll() {
-            if !encoder_button.is_pressed() {
-                protocol.report(Report::DialValue { diff }).unwrap();
+        let suspended = protocol.is_suspended();
+        if suspended != was_suspended {
+            woken_by_button = false;
+            was_suspended = suspended;
+        }
+
+        let host_just_connected = protocol.host_just_connected();
+
+        // Hand the volume level to a freshly (re)started host so it doesn't need to
+        // keep its own copy.
+        if host_just_connected && volume.is_enabled() {
+            protocol.report_ext(ExtReport::VolumeLevel { level: volume.level() }).unwrap();
+        }
 
-                active_led_index = active_led_index.wrapping_add(diff as usize) % LED_COUNT;
+        if host_just_connected {
+            let StartupInfo { reset_flags, boot_reason } = startup_info;
+            protocol.report_ext(ExtReport::Startup { reset_flags, boot_reason }).unwrap();
+            protocol
+                .report_ext(ExtReport::FirmwareSlot {
+                    slot: update::running_slot(),
+                    on_trial: slot_trial.is_pending(),
+                    rolled_back: slot_trial.rolled_back(),
+                })
+                .unwrap();
+
+            if let Some(record) = crash_record.take() {
+                let mut location: ArrayString<[u8; 64]> = ArrayString::new();
+                let _ = write!(location, "panic at {}:{}", record.file(), record.line());
+                protocol.debug(&location);
+                protocol.debug(record.message());
+
+                crash::clear_crash_record(&mut backup_domain);
             }
         }
 
-        // TODO(bschwind) - Report any poll errors back to the USB host if possible.
-        for command in protocol.poll().unwrap() {
+        // Hearing from the host proves the new firmware works well enough to keep it.
+        if slot_trial.is_pending() && !incoming.is_empty() {
+            match firmware_update.confirm_running_slot(&mut backup_domain, &mut watchdog) {
+                Ok(()) => slot_trial.confirmed(),
+                Err(_) => protocol.debug("Failed to confirm the firmware slot"),
+            }
+        }
+
+        // A sleeping host can't send heartbeats, that doesn't mean it's gone.
+        if !incoming.is_empty() || suspended {
+            host_watchdog.feed(now);
+        }
+
+        // Let a returning host know the lights aren't how it left them, once it has said
+        // hello. Until then the report would be dropped, so the reason is kept for later.
+        if protocol.speaks_extensions() && (host_just_connected || !incoming.is_empty()) {
+            if let Some(reason) = host_watchdog.take_triggered(now) {
+                protocol.report_ext(ExtReport::FailsafeTriggered { reason }).unwrap();
+            }
+        }
+
+        for command in incoming {
             match command {
-                Command::Brightness { target, value } => match target {
+                Incoming::Command(Command::Brightness { target, value }) => match target {
                     0 => front_light.set_brightness(value),
                     1 => back_light.set_brightness(value),
                     _ => {},
                 },
-                Command::Temperature { target, value } => match target {
+                Incoming::Command(Command::Temperature { target, value }) => match target {
                     0 => front_light.set_color_temperature(value),
                     1 => back_light.set_color_temperature(value),
                     _ => {},
                 },
-                Command::Led { r, g, b, pulse_mode } => {
+                Incoming::Command(Command::Led { r, g, b, pulse_mode }) => {
                     led_color = Rgb::new_from_u8(r, g, b);
                     led_pulse = pulse_mode;
                 },
-                Command::Bootload => {
+                Incoming::Command(Command::Bootload) if bootload::BOOTLOADER_ALLOWED => {
                     led.set_high().unwrap();
-                    bootload::request_bootloader();
+                    bootload::request_bootloader(&mut backup_domain);
+                },
+                Incoming::Command(Command::Bootload) => {
+                    protocol.debug("Bootload is disabled in release builds");
+                },
+                Incoming::Extension(ExtCommand::SetVolumeMode { enabled }) => {
+                    volume.set_enabled(enabled);
+                },
+                Incoming::Extension(ExtCommand::SetVolumeLevel { level }) => {
+                    volume.set_level(level);
+                    protocol.report_ext(ExtReport::VolumeLevel { level: volume.level() }).unwrap();
+                },
+                Incoming::Extension(ExtCommand::SetVolumeBounds { min, max, step }) => {
+                    volume.set_bounds(min, max, step);
+                },
+                Incoming::Extension(ExtCommand::SetDialAcceleration {
+                    dial,
+                    curve,
+                    max_multiplier,
+                    report_accelerated,
+                }) => match dial {
+                    VOLUME_DIAL => {
+                        counter.set_acceleration(curve, max_multiplier);
+                        report_accelerated_dial[VOLUME_DIAL as usize] = report_accelerated;
+                    },
+                    BRIGHTNESS_DIAL => {
+                        brightness_counter.set_acceleration(curve, max_multiplier);
+                        report_accelerated_dial[BRIGHTNESS_DIAL as usize] = report_accelerated;
+                    },
+                    _ => {},
+                },
+                Incoming::Extension(ExtCommand::SetDialResolution {
+                    dial,
+                    counts_per_detent,
+                    overflow,
+                }) => match dial {
+                    VOLUME_DIAL => counter.set_resolution(counts_per_detent, overflow),
+                    BRIGHTNESS_DIAL => {
+                        brightness_counter.set_resolution(counts_per_detent, overflow)
+                    },
+                    _ => {},
+                },
+                Incoming::Extension(ExtCommand::SetEncoderConfig { dial, config }) => match dial {
+                    VOLUME_DIAL => counter.set_encoder_config(&config),
+                    BRIGHTNESS_DIAL => brightness_counter.set_encoder_config(&config),
+                    _ => {},
+                },
+                Incoming::Extension(ExtCommand::SetGestureTiming { button, timing }) => {
+                    inputs.set_gesture_timing(button, timing);
+                },
+                Incoming::Extension(ExtCommand::SetDebounceTime { button, debounce_time_ms }) => {
+                    inputs.set_debounce_time_ms(button, debounce_time_ms);
+                },
+                #[cfg(not(feature = "midi"))]
+                Incoming::Extension(ExtCommand::SetConsumerControl { config }) => {
+                    protocol.consumer_control().set_config(config);
+                },
+                Incoming::Extension(ExtCommand::SetSuspendBehavior { behavior }) => {
+                    suspend_behavior = behavior;
+                },
+                Incoming::Extension(ExtCommand::SetFailsafe { config }) => {
+                    host_watchdog.configure(config, now);
+                },
+                Incoming::Extension(ExtCommand::SetWatchdogTimeout { timeout_ms }) => {
+                    watchdog.set_timeout_ms(timeout_ms as u32);
+                },
+                Incoming::Extension(ExtCommand::UpdateBegin { size, crc }) => {
+                    let error = firmware_update.begin(size, crc, &mut watchdog).err();
+                    let written = firmware_update.written();
+                    protocol.report_ext(ExtReport::UpdateStatus { error, written }).unwrap();
+                },
+                Incoming::Extension(ExtCommand::UpdateChunk { offset, len, data }) => {
+                    let error = firmware_update
+                        .write_chunk(offset, &data[..len as usize], &mut watchdog)
+                        .err();
+                    let written = firmware_update.written();
+                    protocol.report_ext(ExtReport::UpdateStatus { error, written }).unwrap();
+                },
+                Incoming::Extension(ExtCommand::UpdateFinish { signature }) => {
+                    let error = firmware_update.finish(signature.as_ref(), &mut watchdog).err();
+                    let written = firmware_update.written();
+                    protocol.report_ext(ExtReport::UpdateStatus { error, written }).unwrap();
+
+                    if error.is_none() {
+                        // The host won't hear from the panel again until it has rebooted.
+                        protocol.flush();
+                        firmware_update.install(&mut backup_domain);
+                    }
+                },
+                Incoming::Extension(ExtCommand::UpdateAbort) => {
+                    firmware_update.abort();
+                    let written = firmware_update.written();
+                    protocol.report_ext(ExtReport::UpdateStatus { error: None, written }).unwrap();
                 },
                 _ => {},
             }
         }
 
+        if let Some(scene) = host_watchdog.poll(protocol.is_host_connected(), now) {
+            front_light.set_brightness(scene.brightness);
+            back_light.set_brightness(scene.brightness);
+            led_color = Rgb::new_from_u8(scene.led_r, scene.led_g, scene.led_b);
+            led_pulse = PulseMode::Solid;
+        }
+
+        let lights_dimmed = suspended && !woken_by_button;
+        let lights_scale = if lights_dimmed { suspend_behavior.lights.scale() } else { 1.0 };
+        front_light.set_brightness_scale(lights_scale);
+        back_light.set_brightness_scale(lights_scale);
+
         match led_pulse {
             PulseMode::Breathing { interval_ms } => {
                 pulser.set_interval_ms(u16::from(interval_ms) as u32, &timer);
@@ -242,6 +626,10 @@ fn main() -> ! {
             },
         };
 
+        for target_led_color in target_led_colors.iter_mut() {
+            *target_led_color = *target_led_color * lights_scale;
+        }
+
         // Fade all leds toward the target led colors
         for (current_led_color, target_led_color) in
             current_led_colors.iter_mut().zip(target_led_colors.iter())
@@ -249,5 +637,20 @@ fn main() -> ! {
             current_led_color.fade_towards(target_led_color, FADE_CONSTANT);
         }
         led_strip.set_colors(&current_led_colors);
+
+        // An unconfirmed firmware lets the watchdog reset the MCU, rolling it back.
+        if !slot_trial.has_expired(now) {
+            watchdog.feed();
+        }
+
+        // Nothing is animating, so sleep until an input changes or the next tick.
+        let leds_settled = current_led_colors
+            .iter()
+            .zip(target_led_colors.iter())
+            .all(|(current, target)| current.is_near(target));
+        let lights_off = lights_dimmed && suspend_behavior.lights == SuspendLights::Off;
+        if leds_settled && (lights_off || !matches!(led_pulse, PulseMode::Breathing { .. })) {
+            wakeup::sleep();
+        }
     }
 }
@@ -0,0 +1,209 @@
+use panel_protocol::{ArrayVec, PulseMode};
+use usb_device::{class_prelude::*, UsbError};
+
+use crate::serial::Command;
+
+// A USB MIDI 1.0 streaming interface. The dials send relative control changes, the dial
+// button sends a note, and control changes from the host set the lights.
+const USB_CLASS_AUDIO: u8 = 0x01;
+const AUDIO_SUBCLASS_CONTROL: u8 = 0x01;
+const AUDIO_SUBCLASS_MIDI_STREAMING: u8 = 0x03;
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const HEADER_SUBTYPE: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+const JACK_EMBEDDED: u8 = 0x01;
+const JACK_EXTERNAL: u8 = 0x02;
+
+// Host -> embedded IN jack -> external OUT jack, and external IN jack -> embedded OUT jack
+// -> host.
+const JACK_EMBEDDED_IN: u8 = 1;
+const JACK_EXTERNAL_IN: u8 = 2;
+const JACK_EMBEDDED_OUT: u8 = 3;
+const JACK_EXTERNAL_OUT: u8 = 4;
+
+// The class specific MIDI streaming descriptors: the header, the 4 jacks, and both
+// endpoints with their class specific descriptors.
+const MIDI_STREAMING_TOTAL_LEN: u16 = 7 + 6 + 6 + 9 + 9 + (7 + 5) * 2;
+
+const MAX_PACKET_LEN: usize = 64;
+const EVENT_LEN: usize = 4;
+const MAX_PENDING_EVENTS: usize = MAX_PACKET_LEN / EVENT_LEN;
+// Commands from a single packet of incoming events.
+pub const MAX_MIDI_COMMANDS: usize = MAX_PACKET_LEN / EVENT_LEN;
+
+// Code index numbers, the low nibble of the first byte of a USB MIDI event.
+const CIN_NOTE_OFF: u8 = 0x8;
+const CIN_NOTE_ON: u8 = 0x9;
+const CIN_CONTROL_CHANGE: u8 = 0xB;
+
+const STATUS_NOTE_OFF: u8 = 0x80;
+const STATUS_NOTE_ON: u8 = 0x90;
+const STATUS_CONTROL_CHANGE: u8 = 0xB0;
+
+const CHANNEL: u8 = 0;
+
+/// Dial `n` sends relative changes on CC `DIAL_CC + n`, 64 + the number of detents turned.
+const DIAL_CC: u8 = 16;
+const RELATIVE_CENTER: i16 = 64;
+/// The dial button plays middle C.
+const BUTTON_NOTE: u8 = 60;
+const BUTTON_VELOCITY: u8 = 127;
+
+// Incoming control changes.
+const LED_RED_CC: u8 = 20;
+const LED_GREEN_CC: u8 = 21;
+const LED_BLUE_CC: u8 = 22;
+const FRONT_BRIGHTNESS_CC: u8 = 23;
+const BACK_BRIGHTNESS_CC: u8 = 24;
+
+pub struct Midi<'a, B: UsbBus> {
+    audio_control_interface: InterfaceNumber,
+    streaming_interface: InterfaceNumber,
+    endpoint_out: EndpointOut<'a, B>,
+    endpoint_in: EndpointIn<'a, B>,
+    pending: ArrayVec<[[u8; EVENT_LEN]; MAX_PENDING_EVENTS]>,
+    /// The LED color set through control changes, each of which only sets one component.
+    led: [u8; 3],
+}
+
+impl<'a, B: UsbBus> Midi<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            audio_control_interface: alloc.interface(),
+            streaming_interface: alloc.interface(),
+            endpoint_out: alloc.bulk(MAX_PACKET_LEN as u16),
+            endpoint_in: alloc.bulk(MAX_PACKET_LEN as u16),
+            pending: ArrayVec::new(),
+            led: [0; 3],
+        }
+    }
+
+    /// Sends the movement of a dial as a relative control change.
+    pub fn dial(&mut self, dial: u8, diff: i8) {
+        let value = (RELATIVE_CENTER + diff as i16).clamp(0, 127) as u8;
+        self.push([CIN_CONTROL_CHANGE, STATUS_CONTROL_CHANGE | CHANNEL, DIAL_CC + dial, value]);
+    }
+
+    /// Sends a note on when the dial button is pressed, and a note off when it's released.
+    pub fn button(&mut self, pressed: bool) {
+        let event = if pressed {
+            [CIN_NOTE_ON, STATUS_NOTE_ON | CHANNEL, BUTTON_NOTE, BUTTON_VELOCITY]
+        } else {
+            [CIN_NOTE_OFF, STATUS_NOTE_OFF | CHANNEL, BUTTON_NOTE, 0]
+        };
+        self.push(event);
+    }
+
+    /// Sends the pending events, if the endpoint is ready for them. Called after every poll.
+    pub fn write_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let mut packet: ArrayVec<[u8; MAX_PACKET_LEN]> = ArrayVec::new();
+        for event in &self.pending {
+            let _ = packet.try_extend_from_slice(event);
+        }
+
+        match self.endpoint_in.write(&packet) {
+            Ok(_) => self.pending.clear(),
+            Err(UsbError::WouldBlock) => {},
+            // The host hasn't configured the device. Dial movements are relative, so
+            // replaying them once it has would jump whatever they control.
+            Err(_) => self.pending.clear(),
+        }
+    }
+
+    /// Turns control changes received from the host into commands for the lights.
+    pub fn read_commands(&mut self) -> ArrayVec<[Command; MAX_MIDI_COMMANDS]> {
+        let mut commands = ArrayVec::new();
+
+        let mut packet = [0u8; MAX_PACKET_LEN];
+        let count = match self.endpoint_out.read(&mut packet) {
+            Ok(count) => count,
+            Err(_) => return commands,
+        };
+
+        for event in packet[..count].chunks_exact(EVENT_LEN) {
+            if event[0] & 0x0F != CIN_CONTROL_CHANGE || event[1] & 0xF0 != STATUS_CONTROL_CHANGE {
+                continue;
+            }
+
+            if let Some(command) = self.control_change(event[2], event[3] & 0x7F) {
+                let _ = commands.try_push(command);
+            }
+        }
+
+        commands
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8) -> Option<Command> {
+        let led_component = match controller {
+            LED_RED_CC => 0,
+            LED_GREEN_CC => 1,
+            LED_BLUE_CC => 2,
+            FRONT_BRIGHTNESS_CC | BACK_BRIGHTNESS_CC => {
+                let target = if controller == FRONT_BRIGHTNESS_CC { 0 } else { 1 };
+                let value = (value as u32 * u16::MAX as u32 / 127) as u16;
+                return Some(Command::Brightness { target, value });
+            },
+            _ => return None,
+        };
+
+        self.led[led_component] = (value as u16 * 255 / 127) as u8;
+        let [r, g, b] = self.led;
+        Some(Command::Led { r, g, b, pulse_mode: PulseMode::Solid })
+    }
+
+    fn push(&mut self, event: [u8; EVENT_LEN]) {
+        // Cable number 0, so the first byte is just the code index number.
+        let _ = self.pending.try_push(event);
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for Midi<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        let streaming_interface = u8::from(self.streaming_interface);
+
+        writer.interface(
+            self.audio_control_interface,
+            USB_CLASS_AUDIO,
+            AUDIO_SUBCLASS_CONTROL,
+            0,
+        )?;
+        // Audio control header: ADC 1.0, 9 bytes in total, one streaming interface.
+        writer.write(CS_INTERFACE, &[HEADER_SUBTYPE, 0x00, 0x01, 9, 0, 1, streaming_interface])?;
+
+        writer.interface(
+            self.streaming_interface,
+            USB_CLASS_AUDIO,
+            AUDIO_SUBCLASS_MIDI_STREAMING,
+            0,
+        )?;
+        let total_len = MIDI_STREAMING_TOTAL_LEN.to_le_bytes();
+        writer.write(CS_INTERFACE, &[HEADER_SUBTYPE, 0x00, 0x01, total_len[0], total_len[1]])?;
+
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, JACK_EMBEDDED, JACK_EMBEDDED_IN, 0])?;
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, JACK_EXTERNAL, JACK_EXTERNAL_IN, 0])?;
+        // Output jacks have one input pin each, connected to pin 1 of their source jack.
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_OUT_JACK, JACK_EMBEDDED, JACK_EMBEDDED_OUT, 1, JACK_EXTERNAL_IN, 1, 0],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_OUT_JACK, JACK_EXTERNAL, JACK_EXTERNAL_OUT, 1, JACK_EMBEDDED_IN, 1, 0],
+        )?;
+
+        writer.endpoint(&self.endpoint_out)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, JACK_EMBEDDED_IN])?;
+        writer.endpoint(&self.endpoint_in)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, JACK_EMBEDDED_OUT])
+    }
+}
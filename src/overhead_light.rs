@@ -11,6 +11,8 @@ where
     brightness_c2: P2,
     color_c1: P3,
     color_c2: P4,
+    brightness: u16,
+    brightness_scale: f32,
 }
 
 impl<P1, P2, P3, P4> OverheadLight<P1, P2, P3, P4>
@@ -39,13 +41,35 @@ where
         color_c1.set_duty(0);
         color_c2.set_duty(0);
 
-        OverheadLight { brightness_c1, brightness_c2, color_c1, color_c2 }
+        OverheadLight {
+            brightness_c1,
+            brightness_c2,
+            color_c1,
+            color_c2,
+            brightness: u16::MAX,
+            brightness_scale: 1.0,
+        }
     }
 
     /// Sets the brightness of both channels.
     /// 0 = Off
     /// u16::MAX = Full brightness
     pub fn set_brightness(&mut self, brightness: u16) {
+        self.brightness = brightness;
+        self.apply_brightness();
+    }
+
+    /// Scales the brightness set with `set_brightness()`, without forgetting it.
+    /// 0.0 = Off
+    /// 1.0 = As set
+    pub fn set_brightness_scale(&mut self, scale: f32) {
+        self.brightness_scale = scale.clamp(0.0, 1.0);
+        self.apply_brightness();
+    }
+
+    fn apply_brightness(&mut self) {
+        let brightness = (self.brightness as f32 * self.brightness_scale) as u16;
+
         // Invert the value because our transistor circuit inverts the PWM signal.
         let brightness = u16::MAX - brightness;
 
@@ -0,0 +1,43 @@
+/// What the lights do while the USB bus is suspended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuspendLights {
+    Keep,
+    Dim,
+    Off,
+}
+
+impl SuspendLights {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SuspendLights::Keep),
+            1 => Some(SuspendLights::Dim),
+            2 => Some(SuspendLights::Off),
+            _ => None,
+        }
+    }
+
+    /// The factor to scale the brightness of all lights by while suspended.
+    pub fn scale(&self) -> f32 {
+        match self {
+            SuspendLights::Keep => 1.0,
+            SuspendLights::Dim => 0.25,
+            SuspendLights::Off => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuspendBehavior {
+    pub lights: SuspendLights,
+
+    /// Pressing the button while suspended restores the lights, until the
+    /// bus suspends again.
+    pub wake_on_button: bool,
+}
+
+/// The lights stay as they are until the host asks for something else.
+impl Default for SuspendBehavior {
+    fn default() -> Self {
+        Self { lights: SuspendLights::Keep, wake_on_button: false }
+    }
+}
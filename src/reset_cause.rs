@@ -0,0 +1,89 @@
+use hal::stm32;
+use stm32f4xx_hal as hal;
+
+use crate::backup_domain::{BackupDomain, BackupValue, BOOT_REASON};
+
+// Bits of the reset flags sent to the host, one per RCC_CSR reset flag.
+pub const RESET_PIN: u8 = 1 << 0;
+pub const RESET_POWER_ON: u8 = 1 << 1;
+pub const RESET_SOFTWARE: u8 = 1 << 2;
+pub const RESET_INDEPENDENT_WATCHDOG: u8 = 1 << 3;
+pub const RESET_WINDOW_WATCHDOG: u8 = 1 << 4;
+pub const RESET_LOW_POWER: u8 = 1 << 5;
+pub const RESET_BROWN_OUT: u8 = 1 << 6;
+
+/// Why the firmware itself reset the MCU, stored in the `BOOT_REASON` backup register right
+/// before doing so. The reset flags alone can't tell the different software resets apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootReason {
+    /// The firmware didn't record a reason, the reset flags tell the rest.
+    Unknown,
+
+    /// `bootload::request_bootloader()` rebooted into the ST bootloader.
+    Bootloader,
+
+    /// The panic handler reset the MCU, see `crash`.
+    Panic,
+}
+
+impl BootReason {
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            BootReason::Unknown => 0,
+            BootReason::Bootloader => 1,
+            BootReason::Panic => 2,
+        }
+    }
+
+    fn from_u32(value: u32) -> Self {
+        match value {
+            1 => BootReason::Bootloader,
+            2 => BootReason::Panic,
+            _ => BootReason::Unknown,
+        }
+    }
+}
+
+impl BackupValue for BootReason {
+    fn to_bits(&self) -> u32 {
+        self.as_u32()
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        Self::from_u32(bits)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartupInfo {
+    /// A combination of the `RESET_*` bits.
+    pub reset_flags: u8,
+    pub boot_reason: BootReason,
+}
+
+/// Reads and clears the reset flags and the stored boot reason, so the next boot
+/// starts from a clean slate. This needs to happen before the RCC is handed to the HAL.
+pub fn take_startup_info(rcc: &stm32::RCC, backup_domain: &mut BackupDomain) -> StartupInfo {
+    let csr = rcc.csr.read();
+
+    let flags = [
+        (csr.padrstf().bit_is_set(), RESET_PIN),
+        (csr.porrstf().bit_is_set(), RESET_POWER_ON),
+        (csr.sftrstf().bit_is_set(), RESET_SOFTWARE),
+        (csr.wdgrstf().bit_is_set(), RESET_INDEPENDENT_WATCHDOG),
+        (csr.wwdgrstf().bit_is_set(), RESET_WINDOW_WATCHDOG),
+        (csr.lpwrrstf().bit_is_set(), RESET_LOW_POWER),
+        (csr.borrstf().bit_is_set(), RESET_BROWN_OUT),
+    ];
+    let reset_flags =
+        flags.iter().filter(|(is_set, _)| *is_set).fold(0, |flags, (_, bit)| flags | bit);
+
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
+    let boot_reason = backup_domain.read(&BOOT_REASON);
+    if boot_reason != BootReason::Unknown {
+        backup_domain.write(&BOOT_REASON, BootReason::Unknown);
+    }
+
+    StartupInfo { reset_flags, boot_reason }
+}
@@ -23,6 +23,13 @@ impl Rgb {
         *self = *self * fade_const + *other * (1.0 - fade_const);
     }
 
+    /// True if the colors are too close to tell apart on the LEDs.
+    pub fn is_near(&self, other: &Self) -> bool {
+        libm::fabsf(self.r - other.r) < 0.5
+            && libm::fabsf(self.g - other.g) < 0.5
+            && libm::fabsf(self.b - other.b) < 0.5
+    }
+
     pub fn r(&self) -> u8 {
         self.r.clamp(0.0, 255.0) as u8
     }
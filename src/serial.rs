@@ -1,16 +1,48 @@
 use stm32f4xx_hal as hal;
 
+#[cfg(not(feature = "midi"))]
+use crate::consumer_control::ConsumerControl;
+#[cfg(feature = "midi")]
+use crate::midi::Midi;
+use crate::{
+    dfu::DfuRuntime,
+    extension::{
+        self, ExtCommand, ExtCommandReader, ExtReport, EXT_FRAME_MARKER, MIN_PROTOCOL_VERSION,
+    },
+    framing::{self, FrameError, FrameReader, MAX_ENCODED_FRAME_LEN},
+    transport::Transport,
+    vendor::VendorInterface,
+};
 use hal::{
     otg_fs::{UsbBus, USB},
     serial::{self},
 };
 use panel_protocol::{ArrayString, ArrayVec, MAX_COMMAND_LEN, MAX_COMMAND_QUEUE_LEN};
 pub use panel_protocol::{Command, CommandReader, Report};
-use usb_device::{device::UsbDevice, UsbError};
-use usbd_serial::SerialPort;
+use usb_device::{
+    device::{UsbDevice, UsbDeviceState},
+    UsbError,
+};
+
+// Enough for a few milliseconds of polling. Used to give up on writes and flushes which
+// aren't getting anywhere, as the host may have stopped reading.
+const MAX_FLUSH_POLLS: u32 = 10_000;
+
+// Acks, nacks and debug reports for the commands from a single read.
+const MAX_REPLIES_LEN: usize = 256;
+
+type Replies = ArrayVec<[u8; MAX_REPLIES_LEN]>;
+type EncodedFrame = ArrayVec<[u8; MAX_ENCODED_FRAME_LEN]>;
 
 type Stm32F4UsbDevice = stm32f4xx_hal::otg_fs::UsbBus<stm32f4xx_hal::otg_fs::USB>;
 
+/// The USB class driven by the dials and buttons, next to the serial port. The USB
+/// peripheral only has the IN endpoints for one of them, so the `midi` feature picks.
+#[cfg(not(feature = "midi"))]
+pub type Media<'a> = ConsumerControl<'a, Stm32F4UsbDevice>;
+#[cfg(feature = "midi")]
+pub type Media<'a> = Midi<'a, Stm32F4UsbDevice>;
+
 #[derive(Debug)]
 pub enum Error {
     Serial(hal::serial::Error),
@@ -44,61 +76,848 @@ impl From<panel_protocol::Error> for Error {
     }
 }
 
-pub struct SerialProtocol<'a> {
+/// A command received from the host, either from panel-protocol or one of
+/// the firmware's protocol extensions.
+pub enum Incoming {
+    Command(Command),
+    Extension(ExtCommand),
+}
+
+/// The state of a stream in the framed mode, see `framing`.
+struct Framing {
+    reader: FrameReader,
+    /// The sequence number of the last command accepted, to recognize resent frames.
+    last_seq: Option<u8>,
+    next_report_seq: u8,
+}
+
+impl Framing {
+    fn new() -> Self {
+        Self { reader: FrameReader::new(), last_seq: None, next_report_seq: 0 }
+    }
+
+    /// Consumes bytes up to the end of the current frame, queueing its command and
+    /// replying to it. Returns the number of bytes consumed, and the command if it's
+    /// one for the stream itself.
+    fn process_bytes(
+        &mut self,
+        bytes: &[u8],
+        incoming: &mut ArrayVec<[Incoming; MAX_COMMAND_QUEUE_LEN]>,
+        replies: &mut Replies,
+    ) -> (usize, Option<ExtCommand>) {
+        let (consumed, frame) = self.reader.process_bytes(bytes);
+        let mut stream_command = None;
+
+        let reply = match frame {
+            None => return (consumed, None),
+            Some(Err((seq, error))) => ExtReport::Nack { seq, error },
+            // The host didn't hear the ack, the command has already been taken care of.
+            Some(Ok(frame)) if Some(frame.seq) == self.last_seq => {
+                ExtReport::Ack { seq: frame.seq }
+            },
+            Some(Ok(frame)) => {
+                let seq = frame.seq;
+                let accepted = match parse_message(&frame.message) {
+                    Ok(Incoming::Extension(command)) if is_stream_command(&command) => {
+                        stream_command = Some(command);
+                        Ok(())
+                    },
+                    Ok(command) => incoming.try_push(command).map_err(|_| FrameError::Busy),
+                    Err(error) => Err(error),
+                };
+
+                match accepted {
+                    Ok(()) => {
+                        self.last_seq = Some(seq);
+                        ExtReport::Ack { seq }
+                    },
+                    Err(error) => ExtReport::Nack { seq, error },
+                }
+            },
+        };
+
+        // Without room, the reply is dropped and the host resends the frame.
+        let _ = replies.try_extend_from_slice(&self.encode(&reply.as_arrayvec()));
+
+        (consumed, stream_command)
+    }
+
+    fn encode(&mut self, message: &[u8]) -> EncodedFrame {
+        let seq = self.next_report_seq;
+        self.next_report_seq = seq.wrapping_add(1);
+        framing::encode(seq, message)
+    }
+}
+
+/// Commands about the stream they arrive on, which never reach the main loop.
+fn is_stream_command(command: &ExtCommand) -> bool {
+    matches!(command, ExtCommand::SetFraming { .. } | ExtCommand::Hello { .. })
+}
+
+/// Parses the message of a frame, which has to be exactly one command.
+fn parse_message(message: &[u8]) -> Result<Incoming, FrameError> {
+    if message.first() == Some(&EXT_FRAME_MARKER) {
+        match ExtCommandReader::new().process_bytes(message) {
+            (consumed, Some(Ok(command))) if consumed == message.len() => {
+                Ok(Incoming::Extension(command))
+            },
+            _ => Err(FrameError::Malformed),
+        }
+    } else {
+        let mut commands =
+            CommandReader::new().process_bytes(message).map_err(|_| FrameError::Malformed)?;
+
+        match (commands.pop(), commands.is_empty()) {
+            (Some(command), true) => Ok(Incoming::Command(command)),
+            _ => Err(FrameError::Malformed),
+        }
+    }
+}
+
+/// Splits a byte stream into panel-protocol commands and extension frames, and frames
+/// the reports going the other way in framed mode. Each interface the host can write
+/// to has its own, so they don't garble each other.
+struct StreamCodec {
     protocol: CommandReader,
+    /// True while `protocol` has part of a command, which an extension frame can't interrupt.
+    in_command: bool,
+    ext_protocol: ExtCommandReader,
+    /// Set in framed mode.
+    framing: Option<Framing>,
+    /// The protocol version agreed on with `ExtCommand::Hello`.
+    version: u8,
+    /// Set when a hello switches the stream to the extensions, until `take_greeted()`.
+    greeted: bool,
+    open: bool,
+}
+
+impl StreamCodec {
+    fn new() -> Self {
+        Self {
+            protocol: CommandReader::new(),
+            in_command: false,
+            ext_protocol: ExtCommandReader::new(),
+            framing: None,
+            version: MIN_PROTOCOL_VERSION,
+            greeted: false,
+            open: false,
+        }
+    }
+
+    /// Every host program opening the stream starts out in the legacy mode, speaking the
+    /// oldest protocol until it says hello.
+    fn set_open(&mut self, open: bool) {
+        if open && !self.open {
+            *self = Self::new();
+        }
+        self.open = open;
+    }
+
+    /// Queues the commands in `bytes`. Malformed ones are dropped, in framed mode with a
+    /// nack and otherwise with a debug report, so the host can't bring the panel down.
+    fn process_bytes(
+        &mut self,
+        mut bytes: &[u8],
+        incoming: &mut ArrayVec<[Incoming; MAX_COMMAND_QUEUE_LEN]>,
+        replies: &mut Replies,
+    ) {
+        while let Some(&first_byte) = bytes.first() {
+            if let Some(framing) = &mut self.framing {
+                let (consumed, command) = framing.process_bytes(bytes, incoming, replies);
+                if let Some(command) = command {
+                    self.handle_stream_command(command, replies);
+                }
+
+                bytes = &bytes[consumed..];
+                continue;
+            }
+
+            if self.ext_protocol.is_idle() && (self.in_command || first_byte != EXT_FRAME_MARKER) {
+                // The command reader doesn't tell where a command ends, other than by
+                // returning it, so it's fed a byte at a time.
+                self.in_command = match self.protocol.process_bytes(&bytes[..1]) {
+                    Ok(commands) => {
+                        let in_command = commands.is_empty();
+                        for command in commands {
+                            self.queue(Incoming::Command(command), incoming, replies);
+                        }
+                        in_command
+                    },
+                    Err(_) => {
+                        self.protocol = CommandReader::new();
+                        self.reply_debug("Malformed command", replies);
+                        false
+                    },
+                };
+
+                bytes = &bytes[1..];
+                continue;
+            }
+
+            let (consumed, command) = self.ext_protocol.process_bytes(bytes);
+            match command {
+                Some(Ok(command)) if is_stream_command(&command) => {
+                    self.handle_stream_command(command, replies);
+                },
+                Some(Ok(command)) => self.queue(Incoming::Extension(command), incoming, replies),
+                Some(Err(_)) => self.reply_debug("Malformed extension frame", replies),
+                None => {},
+            }
+
+            bytes = &bytes[consumed..];
+        }
+    }
+
+    fn queue(
+        &mut self,
+        command: Incoming,
+        incoming: &mut ArrayVec<[Incoming; MAX_COMMAND_QUEUE_LEN]>,
+        replies: &mut Replies,
+    ) {
+        if incoming.try_push(command).is_err() {
+            self.reply_debug("Command queue full", replies);
+        }
+    }
+
+    /// Tells the host a command was dropped, outside of framed mode where nacks do that.
+    fn reply_debug(&mut self, message: &str, replies: &mut Replies) {
+        let mut truncated = ArrayString::new();
+        let _ = truncated.try_push_str(message);
+
+        let mut buf = ArrayVec::new();
+        let report = Report::Debug { message: truncated }.as_arrayvec();
+        let _ = replies.try_extend_from_slice(self.encode(&report, &mut buf));
+    }
+
+    fn handle_stream_command(&mut self, command: ExtCommand, replies: &mut Replies) {
+        match command {
+            ExtCommand::SetFraming { framed } => match (framed, &self.framing) {
+                (true, None) => self.framing = Some(Framing::new()),
+                (false, Some(_)) => self.framing = None,
+                _ => {},
+            },
+            ExtCommand::Hello { version } => {
+                let version = extension::negotiate_version(version);
+                // Without a version in common, the host can still decide what to do.
+                if version != 0 {
+                    self.version = version;
+                    self.greeted = self.speaks_extensions();
+                }
+
+                let mut buf = ArrayVec::new();
+                let reply = ExtReport::Hello { version }.as_arrayvec();
+                let _ = replies.try_extend_from_slice(self.encode(&reply, &mut buf));
+            },
+            _ => {},
+        }
+    }
+
+    /// Protocol version 1 predates the extension reports.
+    fn speaks_extensions(&self) -> bool {
+        self.open && self.version >= 2
+    }
+
+    fn take_greeted(&mut self) -> bool {
+        core::mem::replace(&mut self.greeted, false)
+    }
+
+    /// The bytes to send for a report, framed in framed mode.
+    fn encode<'b>(&mut self, report: &'b [u8], buf: &'b mut EncodedFrame) -> &'b [u8] {
+        match &mut self.framing {
+            Some(framing) => {
+                *buf = framing.encode(report);
+                buf
+            },
+            None => report,
+        }
+    }
+}
+
+/// A transport and the stream the host speaks over it. Nothing here knows about the USB
+/// device the transport may be a class of: whenever the transport has to make progress,
+/// `poll_usb` is handed the transport to poll the device with.
+struct Link<T: Transport> {
+    transport: T,
+    codec: StreamCodec,
+    read_buf: [u8; MAX_COMMAND_LEN],
+}
+
+impl<T: Transport> Link<T> {
+    fn new(transport: T) -> Self {
+        Self { transport, codec: StreamCodec::new(), read_buf: [0u8; MAX_COMMAND_LEN] }
+    }
+
+    /// True while the host has the transport open. The stream starts over when it reopens it.
+    fn update_open(&mut self) -> bool {
+        let open = self.transport.is_open();
+        self.codec.set_open(open);
+        open
+    }
+
+    /// Queues the commands which have arrived, and replies to them.
+    fn read(
+        &mut self,
+        incoming: &mut ArrayVec<[Incoming; MAX_COMMAND_QUEUE_LEN]>,
+        poll_usb: &mut dyn FnMut(&mut T),
+    ) -> Result<(), Error> {
+        let mut replies = ArrayVec::new();
+        let count = self.transport.read(&mut self.read_buf[..])?;
+        self.codec.process_bytes(&self.read_buf[..count], incoming, &mut replies);
+        self.write_bytes(&replies, poll_usb);
+        Ok(())
+    }
+
+    /// Sends a report, unless the host wouldn't understand it or isn't there to read it.
+    fn write_report(
+        &mut self,
+        report_bytes: &[u8],
+        extension: bool,
+        poll_usb: &mut dyn FnMut(&mut T),
+    ) {
+        // Nobody is reading the transport, so the report would only fill up its buffer.
+        if !self.transport.is_open() {
+            return;
+        }
+
+        if extension && !self.codec.speaks_extensions() {
+            return;
+        }
+
+        let mut buf = ArrayVec::new();
+        let report_bytes = self.codec.encode(report_bytes, &mut buf);
+        self.write_bytes(report_bytes, poll_usb);
+    }
+
+    /// Drops the rest of the bytes once nothing has gotten through for `MAX_FLUSH_POLLS`,
+    /// or the transport fails. A broken transport isn't worth taking the panel down for.
+    fn write_bytes(&mut self, bytes: &[u8], poll_usb: &mut dyn FnMut(&mut T)) {
+        let mut write_offset = 0;
+        let count = bytes.len();
+        let mut stalled_polls = 0;
+
+        while write_offset < count {
+            match self.transport.write(&bytes[write_offset..count]) {
+                Ok(0) if stalled_polls == MAX_FLUSH_POLLS => break,
+                Ok(0) => {
+                    // A USB transport only makes room once the device has been polled.
+                    poll_usb(&mut self.transport);
+                    stalled_polls += 1;
+                },
+                Ok(written) => {
+                    write_offset += written;
+                    stalled_polls = 0;
+                },
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Pushes out everything written so far, bounded in case the host has stopped reading.
+    fn flush(&mut self, poll_usb: &mut dyn FnMut(&mut T)) {
+        for _ in 0..MAX_FLUSH_POLLS {
+            poll_usb(&mut self.transport);
+
+            match self.transport.flush() {
+                Err(nb::Error::WouldBlock) => {},
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Polls the USB device with all of its classes, `transport` among them if it's one.
+fn poll_usb_device<T: Transport>(
+    usb_device: &mut UsbDevice<'_, UsbBus<USB>>,
+    transport: &mut T,
+    dfu: &mut DfuRuntime,
+    media: &mut Media<'_>,
+    vendor: &mut VendorInterface,
+) {
+    match transport.usb_class() {
+        Some(transport) => {
+            usb_device.poll(&mut [transport, dfu, media, vendor]);
+        },
+        None => {
+            usb_device.poll(&mut [dfu, media, vendor]);
+        },
+    }
+}
+
+pub struct SerialProtocol<'a, T: Transport> {
+    link: Link<T>,
+    vendor_codec: StreamCodec,
     usb_device: UsbDevice<'a, UsbBus<USB>>,
-    usb_serial_device: SerialPort<'a, UsbBus<USB>>,
+    dfu: DfuRuntime,
+    media: Media<'a>,
+    vendor: VendorInterface,
     read_buf: [u8; MAX_COMMAND_LEN],
+    host_connected: bool,
+    host_just_connected: bool,
+    suspended: bool,
 }
 
-impl<'a> SerialProtocol<'a> {
+impl<'a, T: Transport> SerialProtocol<'a, T> {
+    /// The USB device is there for DFU, the media interface and the vendor interface
+    /// even when `transport` isn't USB.
     pub fn new(
         usb_device: usb_device::device::UsbDevice<'a, Stm32F4UsbDevice>,
-        usb_serial_device: usbd_serial::SerialPort<'a, Stm32F4UsbDevice>,
+        transport: T,
+        dfu: DfuRuntime,
+        media: Media<'a>,
+        vendor: VendorInterface,
     ) -> Self {
         Self {
-            protocol: CommandReader::new(),
+            link: Link::new(transport),
+            vendor_codec: StreamCodec::new(),
             usb_device,
-            usb_serial_device,
+            dfu,
+            media,
+            vendor,
             read_buf: [0u8; MAX_COMMAND_LEN],
+            host_connected: false,
+            host_just_connected: false,
+            suspended: false,
         }
     }
 
     /// Check to see if a new command from host is available
-    pub fn poll(&mut self) -> Result<ArrayVec<[Command; MAX_COMMAND_QUEUE_LEN]>, Error> {
-        self.usb_device.poll(&mut [&mut self.usb_serial_device]);
+    pub fn poll(&mut self) -> Result<ArrayVec<[Incoming; MAX_COMMAND_QUEUE_LEN]>, Error> {
+        self.poll_usb_device();
+        self.media.write_pending();
 
-        match self.usb_serial_device.read(&mut self.read_buf[..]) {
-            Ok(count) if count > 0 => {
-                let commands = self.protocol.process_bytes(&self.read_buf[..count])?;
-                Ok(commands)
-            },
-            Ok(_) | Err(UsbError::WouldBlock) => Ok(ArrayVec::new()),
-            Err(e) => Err(e.into()),
+        let transport_open = self.link.update_open();
+        let vendor_open = self.vendor.is_open();
+        self.vendor_codec.set_open(vendor_open);
+
+        let connected = transport_open || vendor_open;
+        if connected && !self.host_connected {
+            self.host_just_connected = true;
+        }
+        self.host_connected = connected;
+
+        // The host suspends the bus when it goes to sleep. That says nothing about a host
+        // on the UART, which may well leave the USB port unconnected.
+        self.suspended = self.link.transport.usb_class().is_some()
+            && self.usb_device.state() == UsbDeviceState::Suspend;
+
+        let mut incoming = ArrayVec::new();
+
+        let Self { link, usb_device, dfu, media, vendor, .. } = self;
+        link.read(&mut incoming, &mut |transport: &mut T| {
+            poll_usb_device(usb_device, transport, dfu, media, vendor)
+        })?;
+
+        let mut replies = ArrayVec::new();
+        let count = self.vendor.read(&mut self.read_buf[..]);
+        self.vendor_codec.process_bytes(&self.read_buf[..count], &mut incoming, &mut replies);
+        self.vendor.write(&replies);
+
+        // The reports for a newly connected host are mostly extension reports, which
+        // wait for it to say hello.
+        if self.link.codec.take_greeted() | self.vendor_codec.take_greeted() {
+            self.host_just_connected = true;
+        }
+
+        // Control changes from MIDI software go through the same dispatch as the serial
+        // commands they stand in for.
+        #[cfg(feature = "midi")]
+        for command in self.media.read_commands() {
+            incoming.try_push(Incoming::Command(command)).map_err(|_| Error::CommandQueueFull)?;
         }
+
+        Ok(incoming)
+    }
+
+    /// True while the host has the transport or the vendor interface open.
+    pub fn is_host_connected(&self) -> bool {
+        self.host_connected
     }
 
-    /// Sends a new report to the host, blocks until fully written or error occurs.
+    /// True while the host has a stream open which takes the extension reports.
+    pub fn speaks_extensions(&self) -> bool {
+        self.link.codec.speaks_extensions() || self.vendor_codec.speaks_extensions()
+    }
+
+    /// Returns true once after the host has opened the transport or the vendor interface,
+    /// and again once it has said hello with a protocol version speaking the extensions.
+    pub fn host_just_connected(&mut self) -> bool {
+        core::mem::replace(&mut self.host_just_connected, false)
+    }
+
+    /// Returns true once after a DFU tool has asked the panel to detach into the bootloader.
+    pub fn dfu_detach_requested(&mut self) -> bool {
+        self.dfu.take_detach_request()
+    }
+
+    /// The media keys sent by the dial and its button.
+    #[cfg(not(feature = "midi"))]
+    pub fn consumer_control(&mut self) -> &mut ConsumerControl<'a, UsbBus<USB>> {
+        &mut self.media
+    }
+
+    /// The MIDI messages sent by the dials and the dial button.
+    #[cfg(feature = "midi")]
+    pub fn midi(&mut self) -> &mut Midi<'a, UsbBus<USB>> {
+        &mut self.media
+    }
+
+    /// True while the USB bus is suspended, for example because the host is asleep.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Sends a new report to the host, blocks until fully written, the host stops reading,
+    /// or an error occurs.
     pub fn report(&mut self, report: Report) -> Result<(), Error> {
-        let report_bytes = report.as_arrayvec();
-        let mut write_offset = 0;
-        let count = report_bytes.len();
+        self.write_all(&report.as_arrayvec(), false)
+    }
 
-        while write_offset < count {
-            match self.usb_serial_device.write(&report_bytes[write_offset..count]) {
-                Ok(len) if len > 0 => {
-                    write_offset += len;
-                },
-                _ => {},
-            }
+    /// Sends a report from one of the protocol extensions to the host, unless it only
+    /// speaks a protocol version without them.
+    pub fn report_ext(&mut self, report: ExtReport) -> Result<(), Error> {
+        self.write_all(&report.as_arrayvec(), true)
+    }
+
+    fn write_all(&mut self, report_bytes: &[u8], extension: bool) -> Result<(), Error> {
+        // Nothing gets through while the bus is suspended, and waiting for it
+        // would block until the host wakes up. Drop the report instead.
+        if self.suspended {
+            return Ok(());
+        }
+
+        if self.vendor.is_open() && (!extension || self.vendor_codec.speaks_extensions()) {
+            let mut buf = ArrayVec::new();
+            self.vendor.write(self.vendor_codec.encode(report_bytes, &mut buf));
         }
 
+        let Self { link, usb_device, dfu, media, vendor, .. } = self;
+        link.write_report(report_bytes, extension, &mut |transport: &mut T| {
+            poll_usb_device(usb_device, transport, dfu, media, vendor)
+        });
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Pushes out everything written so far, for when the main loop is about to stop
+    /// polling the USB device for good.
+    pub fn flush(&mut self) {
+        let Self { link, usb_device, dfu, media, vendor, .. } = self;
+        link.flush(&mut |transport: &mut T| {
+            poll_usb_device(usb_device, transport, dfu, media, vendor)
+        });
+    }
+
+    fn poll_usb_device(&mut self) {
+        poll_usb_device(
+            &mut self.usb_device,
+            &mut self.link.transport,
+            &mut self.dfu,
+            &mut self.media,
+            &mut self.vendor,
+        );
+    }
+
+    /// Sends a debug message to the host, truncated to fit in a single report.
     pub fn debug(&mut self, message: &str) {
-        let report = Report::Debug { message: ArrayString::from(message).unwrap() };
-        let _ = self.report(report);
+        let mut truncated = ArrayString::new();
+        for c in message.chars() {
+            if truncated.try_push(c).is_err() {
+                break;
+            }
+        }
+
+        let _ = self.report(Report::Debug { message: truncated });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::Instant,
+        failsafe::{FailsafeConfig, FailsafeReason, FailsafeScene, HostWatchdog},
+        transport::Loopback,
+    };
+
+    type Queue = ArrayVec<[Incoming; MAX_COMMAND_QUEUE_LEN]>;
+
+    /// Passes `bytes` to `codec`, as if they had been read from the host in one go.
+    fn feed_codec(codec: &mut StreamCodec, bytes: &[u8]) -> (Queue, Replies) {
+        let mut incoming = ArrayVec::new();
+        let mut replies = ArrayVec::new();
+        codec.process_bytes(bytes, &mut incoming, &mut replies);
+        (incoming, replies)
+    }
+
+    #[test]
+    fn extension_frames_between_commands() {
+        let mut bytes: ArrayVec<[u8; 64]> = ArrayVec::new();
+        bytes
+            .try_extend_from_slice(&Command::Brightness { target: 1, value: 500 }.as_arrayvec())
+            .unwrap();
+        bytes.try_extend_from_slice(&[EXT_FRAME_MARKER, b'v', 2, 0, 9]).unwrap();
+        bytes.try_extend_from_slice(&Command::Bootload.as_arrayvec()).unwrap();
+
+        let (incoming, replies) = feed_codec(&mut StreamCodec::new(), &bytes);
+
+        assert_eq!(incoming.len(), 3);
+        assert!(matches!(
+            incoming[0],
+            Incoming::Command(Command::Brightness { target: 1, value: 500 })
+        ));
+        assert!(matches!(
+            incoming[1],
+            Incoming::Extension(ExtCommand::SetVolumeLevel { level: 9 })
+        ));
+        assert!(matches!(incoming[2], Incoming::Command(Command::Bootload)));
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn commands_split_across_reads() {
+        let mut codec = StreamCodec::new();
+        let command = Command::Brightness { target: 0, value: 1000 }.as_arrayvec();
+        let (first, second) = command.split_at(1);
+
+        let (incoming, _) = feed_codec(&mut codec, first);
+        assert!(incoming.is_empty());
+
+        let (incoming, _) = feed_codec(&mut codec, second);
+        assert_eq!(incoming.len(), 1);
+
+        let (incoming, _) = feed_codec(&mut codec, &[EXT_FRAME_MARKER, b'V']);
+        assert!(incoming.is_empty());
+        let (incoming, _) = feed_codec(&mut codec, &[1, 0]);
+        assert!(matches!(
+            incoming[..],
+            [Incoming::Extension(ExtCommand::SetVolumeMode { enabled: false })]
+        ));
+    }
+
+    #[test]
+    fn malformed_extension_frames_are_dropped() {
+        let mut codec = StreamCodec::new();
+        let volume_mode = [EXT_FRAME_MARKER, b'V', 1, 1];
+
+        // An unknown kind, then a valid frame.
+        let (incoming, replies) = feed_codec(&mut codec, &[EXT_FRAME_MARKER, b'?', 1, 0]);
+        assert!(incoming.is_empty());
+        assert!(!replies.is_empty());
+        let (incoming, _) = feed_codec(&mut codec, &volume_mode);
+        assert_eq!(incoming.len(), 1);
+
+        // A payload too long for any command is skipped over.
+        let mut bytes: ArrayVec<[u8; 256]> = ArrayVec::new();
+        bytes.try_extend_from_slice(&[EXT_FRAME_MARKER, b'v', 200]).unwrap();
+        bytes.try_extend_from_slice(&[EXT_FRAME_MARKER; 200]).unwrap();
+        bytes.try_extend_from_slice(&volume_mode).unwrap();
+        let (incoming, _) = feed_codec(&mut codec, &bytes);
+        assert!(matches!(
+            incoming[..],
+            [Incoming::Extension(ExtCommand::SetVolumeMode { enabled: true })]
+        ));
+    }
+
+    /// Decodes `bytes`, which have to be exactly one frame.
+    fn unframe(bytes: &[u8]) -> ArrayVec<[u8; 128]> {
+        match FrameReader::new().process_bytes(bytes) {
+            (consumed, Some(Ok(frame))) if consumed == bytes.len() => {
+                frame.message.iter().copied().collect()
+            },
+            _ => panic!("not a single frame"),
+        }
+    }
+
+    #[test]
+    fn framed_commands_are_acked_once() {
+        let mut codec = StreamCodec::new();
+        let (_, replies) = feed_codec(&mut codec, &[EXT_FRAME_MARKER, b'M', 1, 1]);
+        assert!(replies.is_empty());
+
+        let frame = framing::encode(7, &[EXT_FRAME_MARKER, b'h', 0]);
+        let (incoming, replies) = feed_codec(&mut codec, &frame);
+        assert!(matches!(incoming[..], [Incoming::Extension(ExtCommand::Heartbeat)]));
+        assert_eq!(unframe(&replies), ExtReport::Ack { seq: 7 }.as_arrayvec()[..]);
+
+        // The host missed the ack and resent the frame.
+        let (incoming, replies) = feed_codec(&mut codec, &frame);
+        assert!(incoming.is_empty());
+        assert_eq!(unframe(&replies), ExtReport::Ack { seq: 7 }.as_arrayvec()[..]);
+
+        let mut frame = framing::encode(8, &[EXT_FRAME_MARKER, b'h', 0]);
+        // Corrupts the message, past the sequence number.
+        frame[3] ^= 0x01;
+        let (incoming, replies) = feed_codec(&mut codec, &frame);
+        assert!(incoming.is_empty());
+        assert_eq!(
+            unframe(&replies),
+            ExtReport::Nack { seq: 8, error: FrameError::Crc }.as_arrayvec()[..]
+        );
+    }
+
+    #[test]
+    fn frames_hold_exactly_one_command() {
+        let mut codec = StreamCodec::new();
+        feed_codec(&mut codec, &[EXT_FRAME_MARKER, b'M', 1, 1]);
+
+        let command = Command::Bootload.as_arrayvec();
+        let (incoming, replies) = feed_codec(&mut codec, &framing::encode(1, &command));
+        assert!(matches!(incoming[..], [Incoming::Command(Command::Bootload)]));
+        assert_eq!(unframe(&replies), ExtReport::Ack { seq: 1 }.as_arrayvec()[..]);
+
+        let two_frames = [EXT_FRAME_MARKER, b'h', 0, EXT_FRAME_MARKER, b'h', 0];
+        let (incoming, replies) = feed_codec(&mut codec, &framing::encode(2, &two_frames));
+        assert!(incoming.is_empty());
+        assert_eq!(
+            unframe(&replies),
+            ExtReport::Nack { seq: 2, error: FrameError::Malformed }.as_arrayvec()[..]
+        );
+
+        // Switching back takes a frame too.
+        let (_, replies) =
+            feed_codec(&mut codec, &framing::encode(3, &[EXT_FRAME_MARKER, b'M', 1, 0]));
+        assert_eq!(unframe(&replies), ExtReport::Ack { seq: 3 }.as_arrayvec()[..]);
+        let (incoming, _) = feed_codec(&mut codec, &command);
+        assert!(matches!(incoming[..], [Incoming::Command(Command::Bootload)]));
+    }
+
+    #[test]
+    fn extension_reports_wait_for_hello() {
+        let mut codec = StreamCodec::new();
+        codec.set_open(true);
+        assert!(!codec.speaks_extensions());
+
+        // A host too old for any version in common stays on the oldest one.
+        let (_, replies) = feed_codec(&mut codec, &[EXT_FRAME_MARKER, b'H', 1, 0]);
+        assert_eq!((replies[1], replies[3]), (b'H', 0));
+        assert!(!codec.speaks_extensions());
+        assert!(!codec.take_greeted());
+
+        let (_, replies) = feed_codec(&mut codec, &[EXT_FRAME_MARKER, b'H', 1, 2]);
+        assert_eq!((replies[1], replies[3]), (b'H', 2));
+        assert!(codec.speaks_extensions());
+        assert!(codec.take_greeted());
+        assert!(!codec.take_greeted());
+
+        // Reopening the stream starts over.
+        codec.set_open(false);
+        codec.set_open(true);
+        assert!(!codec.speaks_extensions());
+    }
+
+    type HostBytes = ArrayVec<[u8; 256]>;
+
+    /// Polls `link` like the main loop does, without a USB device.
+    fn poll_link(link: &mut Link<Loopback>) -> Queue {
+        let mut incoming = ArrayVec::new();
+        link.update_open();
+        link.read(&mut incoming, &mut |_| {}).unwrap();
+        incoming
+    }
+
+    /// What the panel has written and the host would read.
+    fn host_read(link: &mut Link<Loopback>) -> HostBytes {
+        let mut buf = [0u8; 256];
+        let count = Transport::read(&mut link.transport, &mut buf).unwrap();
+        buf[..count].iter().copied().collect()
+    }
+
+    fn host_write(link: &mut Link<Loopback>, bytes: &[u8]) {
+        assert_eq!(Transport::write(&mut link.transport, bytes).unwrap(), bytes.len());
+    }
+
+    #[test]
+    fn commands_and_reports_round_trip() {
+        let mut link = Link::new(Loopback::new());
+        let press = ExtReport::ButtonPress { button: 0 }.as_arrayvec();
+
+        host_write(&mut link, &Command::Brightness { target: 1, value: 500 }.as_arrayvec());
+        assert!(matches!(
+            poll_link(&mut link)[..],
+            [Incoming::Command(Command::Brightness { target: 1, value: 500 })]
+        ));
+
+        link.write_report(&Report::Press.as_arrayvec(), false, &mut |_| {});
+        assert_eq!(host_read(&mut link), Report::Press.as_arrayvec()[..]);
+        link.write_report(&press, true, &mut |_| {});
+        assert!(host_read(&mut link).is_empty());
+
+        host_write(&mut link, &[EXT_FRAME_MARKER, b'H', 1, 2]);
+        assert!(poll_link(&mut link).is_empty());
+        assert_eq!(host_read(&mut link), ExtReport::Hello { version: 2 }.as_arrayvec()[..]);
+        assert!(link.codec.take_greeted());
+
+        link.write_report(&press, true, &mut |_| {});
+        assert_eq!(host_read(&mut link), press[..]);
+    }
+
+    #[test]
+    fn closed_transports_are_skipped() {
+        let mut link = Link::new(Loopback::new());
+        link.transport.open = false;
+        assert!(!link.update_open());
+
+        let mut polls = 0;
+        link.write_report(&Report::Press.as_arrayvec(), false, &mut |_| polls += 1);
+        assert_eq!(polls, 0);
+        assert!(host_read(&mut link).is_empty());
+    }
+
+    #[test]
+    fn stalled_writes_give_up() {
+        let mut link = Link::new(Loopback::new());
+        link.transport.stalled = true;
+
+        let mut polls = 0;
+        link.write_report(&Report::Press.as_arrayvec(), false, &mut |_| polls += 1);
+        assert_eq!(polls, MAX_FLUSH_POLLS);
+
+        // The next report gets through once the host reads again.
+        link.transport.stalled = false;
+        link.write_report(&Report::Release.as_arrayvec(), false, &mut |_| {});
+        assert_eq!(host_read(&mut link), Report::Release.as_arrayvec()[..]);
+    }
+
+    #[test]
+    fn write_errors_drop_the_report() {
+        let mut link = Link::new(Loopback::new());
+        // The host has stopped reading, and the buffer has filled up.
+        host_write(&mut link, &[0u8; 256]);
+
+        let mut polls = 0;
+        link.write_report(&Report::Press.as_arrayvec(), false, &mut |_| polls += 1);
+        assert_eq!(polls, 0);
+        assert_eq!(host_read(&mut link).len(), 256);
+    }
+
+    #[test]
+    fn failsafe_reports_wait_for_hello() {
+        let scene = FailsafeScene { brightness: 0, led_r: 255, led_g: 0, led_b: 0 };
+        let mut watchdog = HostWatchdog::new(Instant::from_ms(0));
+        watchdog
+            .configure(FailsafeConfig { enabled: true, timeout_ms: 0, scene }, Instant::from_ms(0));
+
+        let mut link = Link::new(Loopback::new());
+        poll_link(&mut link);
+        watchdog.poll(link.update_open(), Instant::from_ms(10));
+        link.transport.open = false;
+        assert!(watchdog.poll(link.update_open(), Instant::from_ms(20)).is_some());
+
+        // The host is back, but takes a while to say hello. Like the main loop, the reason
+        // is only taken once the report would get through.
+        link.transport.open = true;
+        host_write(&mut link, &Command::Bootload.as_arrayvec());
+        assert_eq!(poll_link(&mut link).len(), 1);
+        assert!(!link.codec.speaks_extensions());
+
+        host_write(&mut link, &[EXT_FRAME_MARKER, b'H', 1, 2]);
+        poll_link(&mut link);
+        assert!(link.codec.take_greeted() && link.codec.speaks_extensions());
+        host_read(&mut link);
+
+        let reason = watchdog.take_triggered(Instant::from_ms(30)).unwrap();
+        assert_eq!(reason, FailsafeReason::HostDisconnected);
+        let report = ExtReport::FailsafeTriggered { reason }.as_arrayvec();
+        link.write_report(&report, true, &mut |_| {});
+        assert_eq!(host_read(&mut link), report[..]);
     }
 }
@@ -0,0 +1,222 @@
+// The flash layout shared by the firmware, its build script and the resident bootloader
+// in bootloader/, which include this file with #[path]. It mustn't depend on anything.
+//
+//   0x0800_0000   32K  sectors 0-1  resident bootloader
+//   0x0800_8000   16K  sector 2     boot state, the record of the active slot
+//   0x0800_C000   16K  sector 3     unused
+//   0x0801_0000  192K  sectors 4-5  slot A
+//   0x0804_0000  256K  sectors 6-7  slot B, only the first 192K is used
+//
+// The firmware is linked for one of the slots (see build.rs) and updates are written to the
+// other one. A freshly installed firmware runs on trial: it's booted once, and unless it
+// confirms it's healthy by making its slot the active one, the next reset rolls back.
+
+use core::ptr;
+
+pub const BOOT_STATE_START: u32 = 0x0800_8000;
+pub const BOOT_STATE_SECTOR: u32 = 2;
+pub const BOOT_STATE_SIZE: u32 = 16 * 1024;
+
+pub const SLOT_SIZE: u32 = 192 * 1024;
+
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2002_0000;
+
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+// A boot state record is two words: ACTIVE_RECORD_MAGIC and the slot, then the inverse of
+// the first word, so a record cut short by a power loss is ignored. Records are appended,
+// the last complete one wins.
+const ACTIVE_RECORD_MAGIC: u32 = 0xB007_5100;
+const RECORD_LEN: u32 = 8;
+
+// The trial state is kept in a backup register, see `Trial`.
+pub const TRIAL_REGISTER: usize = 2;
+const TRIAL_MAGIC: u32 = 0x7E1A_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotId {
+    A,
+    B,
+}
+
+impl SlotId {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SlotId::A),
+            1 => Some(SlotId::B),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            SlotId::A => 0,
+            SlotId::B => 1,
+        }
+    }
+
+    pub fn start(&self) -> u32 {
+        match self {
+            SlotId::A => 0x0801_0000,
+            SlotId::B => 0x0804_0000,
+        }
+    }
+
+    /// The flash sectors to erase before writing an image to the slot.
+    pub fn sectors(&self) -> &'static [u32] {
+        match self {
+            SlotId::A => &[4, 5],
+            SlotId::B => &[6, 7],
+        }
+    }
+
+    pub fn other(&self) -> Self {
+        match self {
+            SlotId::A => SlotId::B,
+            SlotId::B => SlotId::A,
+        }
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        (self.start()..self.start() + SLOT_SIZE).contains(&address)
+    }
+}
+
+/// Where a freshly installed firmware is in its trial, kept in `TRIAL_REGISTER`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trial {
+    None,
+
+    /// The firmware has installed an image in the slot, to be booted on the next reset.
+    Pending(SlotId),
+
+    /// The bootloader has booted the slot once, it's up to the firmware to confirm it.
+    Booted(SlotId),
+
+    /// The slot never confirmed, so the bootloader went back to the active slot.
+    RolledBack(SlotId),
+}
+
+impl Trial {
+    pub fn to_bits(&self) -> u32 {
+        match self {
+            Trial::None => 0,
+            Trial::Pending(slot) => TRIAL_MAGIC | 1 << 8 | slot.as_u8() as u32,
+            Trial::Booted(slot) => TRIAL_MAGIC | 2 << 8 | slot.as_u8() as u32,
+            Trial::RolledBack(slot) => TRIAL_MAGIC | 3 << 8 | slot.as_u8() as u32,
+        }
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        if bits & 0xFFFF_0000 != TRIAL_MAGIC {
+            return Trial::None;
+        }
+
+        match (bits >> 8 & 0xFF, SlotId::from_u8(bits as u8)) {
+            (1, Some(slot)) => Trial::Pending(slot),
+            (2, Some(slot)) => Trial::Booted(slot),
+            (3, Some(slot)) => Trial::RolledBack(slot),
+            _ => Trial::None,
+        }
+    }
+}
+
+/// The slot recorded by the last confirmed firmware, if any.
+pub fn read_active_slot() -> Option<SlotId> {
+    let mut active = None;
+
+    let mut address = BOOT_STATE_START;
+    while address < BOOT_STATE_START + BOOT_STATE_SIZE {
+        let (word, check) = read_record(address);
+        if word == ERASED_WORD {
+            break;
+        }
+
+        if word == !check && word & !0xFF == ACTIVE_RECORD_MAGIC {
+            active = SlotId::from_u8(word as u8).or(active);
+        }
+
+        address += RECORD_LEN;
+    }
+
+    active
+}
+
+/// The address to program the next boot state record at, or `None` if the sector is full.
+pub fn free_record_address() -> Option<u32> {
+    (BOOT_STATE_START..BOOT_STATE_START + BOOT_STATE_SIZE)
+        .step_by(RECORD_LEN as usize)
+        .find(|&address| read_record(address) == (ERASED_WORD, ERASED_WORD))
+}
+
+/// The two words of the boot state record making `slot` the active one.
+pub fn active_record(slot: SlotId) -> [u32; 2] {
+    let word = ACTIVE_RECORD_MAGIC | slot.as_u8() as u32;
+    [word, !word]
+}
+
+/// Checks that the slot holds something that looks like firmware linked for it: the
+/// vector table must start with a stack pointer in RAM and a reset vector in the slot.
+pub fn image_is_valid(slot: SlotId) -> bool {
+    // Safety: Both words are inside the flash.
+    let (initial_sp, reset_vector) = unsafe {
+        (
+            ptr::read_volatile(slot.start() as *const u32),
+            ptr::read_volatile((slot.start() + 4) as *const u32),
+        )
+    };
+
+    (RAM_START..=RAM_END).contains(&initial_sp) && slot.contains(reset_vector)
+}
+
+fn read_record(address: u32) -> (u32, u32) {
+    // Safety: Only called with addresses inside the boot state sector.
+    unsafe {
+        (ptr::read_volatile(address as *const u32), ptr::read_volatile((address + 4) as *const u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_fit_their_sectors() {
+        // The F411's 16 KiB, 64 KiB and 128 KiB sectors, from 0x0800_0000.
+        let sector_start = |sector: u32| match sector {
+            0..=3 => 0x0800_0000 + sector * 0x4000,
+            4 => 0x0801_0000,
+            _ => 0x0802_0000 + (sector - 5) * 0x2_0000,
+        };
+
+        assert_eq!(sector_start(BOOT_STATE_SECTOR), BOOT_STATE_START);
+        assert_eq!(sector_start(BOOT_STATE_SECTOR + 1), BOOT_STATE_START + BOOT_STATE_SIZE);
+
+        for &slot in &[SlotId::A, SlotId::B] {
+            let sectors = slot.sectors();
+            // Erasing the sectors clears the whole slot.
+            assert_eq!(sector_start(sectors[0]), slot.start());
+            assert!(sector_start(sectors[sectors.len() - 1] + 1) >= slot.start() + SLOT_SIZE);
+        }
+    }
+
+    #[test]
+    fn slots_do_not_overlap() {
+        assert!(!SlotId::A.contains(BOOT_STATE_START + BOOT_STATE_SIZE - 1));
+        assert!(SlotId::A.contains(SlotId::A.start()));
+        assert!(SlotId::A.contains(SlotId::A.start() + SLOT_SIZE - 1));
+        assert!(!SlotId::A.contains(SlotId::B.start()));
+        assert!(SlotId::B.contains(SlotId::B.start()));
+        assert_eq!(SlotId::A.other(), SlotId::B);
+        assert_eq!(SlotId::B.other(), SlotId::A);
+    }
+
+    #[test]
+    fn trials_need_their_magic() {
+        let bits = Trial::Pending(SlotId::B).to_bits();
+        assert_eq!(Trial::from_bits(bits), Trial::Pending(SlotId::B));
+        assert_eq!(Trial::from_bits(bits & 0xFFFF), Trial::None);
+        assert_eq!(Trial::from_bits(TRIAL_MAGIC | 1 << 8 | 7), Trial::None);
+    }
+}
@@ -0,0 +1,116 @@
+use stm32f4xx_hal as hal;
+
+use crate::serial::Error;
+use hal::otg_fs::{UsbBus, USB};
+#[cfg(test)]
+use panel_protocol::ArrayVec;
+use usb_device::{class_prelude::UsbClass, UsbError};
+use usbd_serial::SerialPort;
+
+/// The byte stream the host talks to the panel over.
+pub trait Transport {
+    /// Reads whatever has arrived, `Ok(0)` if there's nothing.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Writes as much of `bytes` as fits right now, `Ok(0)` if nothing does.
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Error>;
+
+    /// Pushes out everything written so far.
+    fn flush(&mut self) -> nb::Result<(), Error>;
+
+    /// True while there's a host on the other end.
+    fn is_open(&self) -> bool;
+
+    /// The class to poll with the other USB classes, for transports that are one.
+    fn usb_class(&mut self) -> Option<&mut dyn UsbClass<UsbBus<USB>>> {
+        None
+    }
+}
+
+impl Transport for SerialPort<'_, UsbBus<USB>> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match SerialPort::read(self, buf) {
+            Ok(count) => Ok(count),
+            Err(UsbError::WouldBlock) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        match SerialPort::write(self, bytes) {
+            Ok(count) => Ok(count),
+            Err(UsbError::WouldBlock) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        match SerialPort::flush(self) {
+            Ok(()) => Ok(()),
+            Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(e) => Err(nb::Error::Other(e.into())),
+        }
+    }
+
+    /// The host opens the port by raising DTR.
+    fn is_open(&self) -> bool {
+        self.dtr()
+    }
+
+    fn usb_class(&mut self) -> Option<&mut dyn UsbClass<UsbBus<USB>>> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+const LOOPBACK_BUFFER_LEN: usize = 256;
+
+/// Reads back what was written, to exercise the protocol without a host.
+#[cfg(test)]
+pub struct Loopback {
+    buffer: ArrayVec<[u8; LOOPBACK_BUFFER_LEN]>,
+    /// Whether there's a host on the other end.
+    pub open: bool,
+    /// Takes no more bytes, like a transport the host has stopped reading.
+    pub stalled: bool,
+}
+
+#[cfg(test)]
+impl Loopback {
+    pub fn new() -> Self {
+        Self { buffer: ArrayVec::new(), open: true, stalled: false }
+    }
+}
+
+#[cfg(test)]
+impl Transport for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let count = self.buffer.len().min(buf.len());
+        buf[..count].copy_from_slice(&self.buffer[..count]);
+        self.buffer.drain(..count);
+        Ok(count)
+    }
+
+    /// Fails once the buffer is full, nothing would ever make room.
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        if self.stalled {
+            return Ok(0);
+        }
+
+        if self.buffer.is_full() && !bytes.is_empty() {
+            return Err(Error::BufferFull);
+        }
+
+        let count = self.buffer.remaining_capacity().min(bytes.len());
+        let _ = self.buffer.try_extend_from_slice(&bytes[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+}
@@ -0,0 +1,278 @@
+use stm32f4xx_hal as hal;
+
+use crate::{
+    clock::{Clock, Instant},
+    serial::Error,
+    transport::Transport,
+};
+use core::{
+    cell::{Cell, UnsafeCell},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+use embedded_hal::serial::Write;
+use hal::{
+    serial::{Event, Serial, Tx},
+    stm32::{self, interrupt, Interrupt, NVIC},
+};
+
+// Without DTR, the host counts as there for this long after it last sent anything.
+const OPEN_TIMEOUT_MS: u32 = 5000;
+
+// A power of two, so the positions stay in step when the counts wrap around.
+const RX_RING_LEN: usize = 256;
+
+/// Bytes received by an interrupt handler, until the main loop gets to them.
+struct RxRing {
+    bytes: UnsafeCell<[u8; RX_RING_LEN]>,
+    /// The number of bytes pushed and read so far, wrapping around.
+    pushed: AtomicUsize,
+    read: AtomicUsize,
+}
+
+// Safety: `push()` only writes the slots `read()` is done with, and `read()` only reads
+// the slots `push()` has filled, see their safety requirements.
+unsafe impl Sync for RxRing {}
+
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            bytes: UnsafeCell::new([0u8; RX_RING_LEN]),
+            pushed: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds a byte, or returns false if the ring is full and the byte is lost.
+    ///
+    /// # Safety
+    /// Only one context, such as a single interrupt handler, may push.
+    unsafe fn push(&self, byte: u8) -> bool {
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        if pushed.wrapping_sub(self.read.load(Ordering::Acquire)) == RX_RING_LEN {
+            return false;
+        }
+
+        // The slot is only read once `pushed` says it's there.
+        *(self.bytes.get() as *mut u8).add(pushed % RX_RING_LEN) = byte;
+        self.pushed.store(pushed.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pushed.load(Ordering::Acquire) == self.read.load(Ordering::Relaxed)
+    }
+
+    /// Moves as many bytes as fit into `buf`, returning how many.
+    ///
+    /// # Safety
+    /// Only one context, such as the main loop, may read.
+    unsafe fn read(&self, buf: &mut [u8]) -> usize {
+        let read = self.read.load(Ordering::Relaxed);
+        let count = self.pushed.load(Ordering::Acquire).wrapping_sub(read).min(buf.len());
+
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = *(self.bytes.get() as *const u8).add(read.wrapping_add(i) % RX_RING_LEN);
+        }
+
+        // The slots can be pushed to again.
+        self.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
+
+// The bytes received on USART1, by its interrupt.
+static UART_RX: RxRing = RxRing::new();
+// The last receive error since the main loop looked, see `rx_error()`. 0 for none.
+static UART_RX_ERROR: AtomicU8 = AtomicU8::new(0);
+
+/// A hardware USART, for panels driven by an embedded host instead of a computer.
+/// Bytes are received by interrupt, so none are lost while the main loop sleeps or is
+/// busy, as long as it gets to them before `RX_RING_LEN` have piled up.
+pub struct UartTransport<'a> {
+    tx: Tx<stm32::USART1>,
+    clock: &'a Clock,
+    last_received: Option<Instant>,
+    /// What `is_open()` said last, bytes are only read once the stream is open.
+    open: Cell<bool>,
+}
+
+impl<'a> UartTransport<'a> {
+    pub fn new<PINS>(mut serial: Serial<stm32::USART1, PINS>, clock: &'a Clock) -> Self {
+        serial.listen(Event::Rxne);
+        let (tx, _rx) = serial.split();
+
+        // Safety: The handler only touches the receive side, which nothing else uses.
+        // It also wakes the main loop from `wakeup::sleep()`.
+        unsafe {
+            NVIC::unmask(Interrupt::USART1);
+        }
+
+        Self { tx, clock, last_received: None, open: Cell::new(false) }
+    }
+}
+
+impl Transport for UartTransport<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        // Bytes which arrived after `is_open()` said the stream is closed have to wait, or
+        // the stream would start over right after they've been read, losing them.
+        if !self.open.get() {
+            return Ok(0);
+        }
+
+        // Safety: The main loop is the only reader.
+        let count = unsafe { UART_RX.read(buf) };
+        if count > 0 {
+            self.last_received = Some(self.clock.now());
+        }
+
+        match rx_error(UART_RX_ERROR.swap(0, Ordering::Relaxed)) {
+            // Overruns and line noise, the bytes so far are suspect too.
+            Some(e) => Err(e.into()),
+            None => Ok(count),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        let mut count = 0;
+
+        for &byte in bytes {
+            match self.tx.write(byte) {
+                Ok(()) => count += 1,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => return Err(e.into()),
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        self.tx.flush().map_err(|e| e.map(Error::from))
+    }
+
+    /// There's no way to tell whether anything is connected, so the host is assumed to be
+    /// while it keeps sending.
+    fn is_open(&self) -> bool {
+        let recently_received = self
+            .last_received
+            .map_or(false, |at| !self.clock.now().has_elapsed(at, OPEN_TIMEOUT_MS));
+        let open = recently_received || !UART_RX.is_empty();
+
+        self.open.set(open);
+        open
+    }
+}
+
+fn rx_error_code(error: hal::serial::Error) -> u8 {
+    match error {
+        hal::serial::Error::Framing => 1,
+        hal::serial::Error::Noise => 2,
+        hal::serial::Error::Parity => 3,
+        _ => 4,
+    }
+}
+
+fn rx_error(code: u8) -> Option<hal::serial::Error> {
+    match code {
+        0 => None,
+        1 => Some(hal::serial::Error::Framing),
+        2 => Some(hal::serial::Error::Noise),
+        3 => Some(hal::serial::Error::Parity),
+        _ => Some(hal::serial::Error::Overrun),
+    }
+}
+
+#[interrupt]
+fn USART1() {
+    // Safety: Reading the status and then the data register clears the receive flags.
+    // Only this handler reads them.
+    let usart = unsafe { &*stm32::USART1::ptr() };
+    let status = usart.sr.read();
+    if status.rxne().bit_is_clear() && status.ore().bit_is_clear() {
+        return;
+    }
+    let byte = usart.dr.read().dr().bits() as u8;
+
+    let error = if status.pe().bit_is_set() {
+        Some(hal::serial::Error::Parity)
+    } else if status.fe().bit_is_set() {
+        Some(hal::serial::Error::Framing)
+    } else if status.nf().bit_is_set() {
+        Some(hal::serial::Error::Noise)
+    } else if status.ore().bit_is_set() {
+        Some(hal::serial::Error::Overrun)
+    } else {
+        None
+    };
+
+    // Safety: This handler is the only writer.
+    let error = match error {
+        None if unsafe { UART_RX.push(byte) } => return,
+        // The main loop hasn't kept up.
+        None => hal::serial::Error::Overrun,
+        Some(error) => error,
+    };
+    UART_RX_ERROR.store(rx_error_code(error), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_come_out_in_order() {
+        let ring = RxRing::new();
+        let mut buf = [0u8; 4];
+
+        unsafe {
+            assert_eq!(ring.read(&mut buf), 0);
+            for byte in 1..=6 {
+                assert!(ring.push(byte));
+            }
+            assert!(!ring.is_empty());
+
+            assert_eq!(ring.read(&mut buf), 4);
+            assert_eq!(buf, [1, 2, 3, 4]);
+            assert_eq!(ring.read(&mut buf), 2);
+            assert_eq!(buf[..2], [5, 6]);
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn full_rings_drop_bytes() {
+        let ring = RxRing::new();
+        let mut buf = [0u8; RX_RING_LEN];
+
+        unsafe {
+            for i in 0..RX_RING_LEN {
+                assert!(ring.push(i as u8));
+            }
+            assert!(!ring.push(0xFF));
+
+            assert_eq!(ring.read(&mut buf[..1]), 1);
+            assert!(ring.push(0xFF));
+
+            assert_eq!(ring.read(&mut buf), RX_RING_LEN);
+            assert_eq!(buf[0], 1);
+            assert_eq!(buf[RX_RING_LEN - 1], 0xFF);
+        }
+    }
+
+    #[test]
+    fn counts_wrap_around() {
+        let ring = RxRing::new();
+        // Close to where the counts wrap, as after a long uptime.
+        ring.pushed.store(usize::MAX - 2, Ordering::Relaxed);
+        ring.read.store(usize::MAX - 2, Ordering::Relaxed);
+        let mut buf = [0u8; 8];
+
+        unsafe {
+            for byte in 0..8 {
+                assert!(ring.push(byte));
+            }
+            assert_eq!(ring.read(&mut buf), 8);
+        }
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+}
@@ -0,0 +1,411 @@
+use hal::stm32::FLASH;
+use stm32f4xx_hal as hal;
+
+use crate::{
+    backup_domain::{BackupDomain, BOOT_TRIAL},
+    clock::Instant,
+    crc::Crc32,
+    slots::{self, SlotId, Trial},
+    watchdog::Watchdog,
+};
+use core::ptr;
+use ed25519_compact::{PublicKey, Signature};
+
+/// The most image bytes carried by a single chunk command.
+pub const UPDATE_CHUNK_LEN: usize = 64;
+
+/// The length of the Ed25519 signature of an image.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The key images have to be signed with: the development key with the `dev-key` feature,
+/// otherwise the release key given by PANEL_RELEASE_KEY at build time, see build.rs.
+const UPDATE_PUBLIC_KEY: Option<[u8; 32]> = include!(concat!(env!("OUT_DIR"), "/update_key.rs"));
+
+// Verifying a signature takes a while on this MCU, and can't stop to feed the watchdog.
+const VERIFY_WATCHDOG_TIMEOUT_MS: u32 = 8000;
+// Erasing a 128K sector takes up to 2 seconds, during which instruction fetches from the
+// flash stall, so nothing runs to feed the watchdog.
+const ERASE_WATCHDOG_TIMEOUT_MS: u32 = 4000;
+
+/// How long a freshly installed firmware has to hear from the host before it's rolled back.
+const TRIAL_TIMEOUT_MS: u32 = 30_000;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+const CR_PG: u32 = 1 << 0;
+const CR_SER: u32 = 1 << 1;
+const CR_SNB_SHIFT: u32 = 3;
+// 32 bit parallelism, valid for our 3.3V supply.
+const CR_PSIZE_X32: u32 = 0b10 << 8;
+const CR_STRT: u32 = 1 << 16;
+const SR_BSY: u32 = 1 << 16;
+// PGSERR, PGPERR, PGAERR, WRPERR and OPERR.
+const SR_ERRORS: u32 = 0b1111_0010;
+
+/// The slot this firmware was linked for, and so is running from.
+pub fn running_slot() -> SlotId {
+    if cfg!(feature = "slot-b") {
+        SlotId::B
+    } else {
+        SlotId::A
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateError {
+    /// A chunk or finish command arrived without a successful begin.
+    NotStarted,
+    TooLarge,
+    /// Chunks must arrive in order, 4 byte aligned.
+    BadOffset,
+    FlashError,
+    CrcMismatch,
+    /// The image doesn't look like firmware linked for the slot it's written to.
+    InvalidImage,
+    /// The image came without a signature.
+    Unsigned,
+    /// The signature doesn't match the image and the firmware's key.
+    BadSignature,
+    /// The firmware was built without a key, so it can't accept any update.
+    NoUpdateKey,
+}
+
+impl UpdateError {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            UpdateError::NotStarted => 1,
+            UpdateError::TooLarge => 2,
+            UpdateError::BadOffset => 3,
+            UpdateError::FlashError => 4,
+            UpdateError::CrcMismatch => 5,
+            UpdateError::InvalidImage => 6,
+            UpdateError::Unsigned => 7,
+            UpdateError::BadSignature => 8,
+            UpdateError::NoUpdateKey => 9,
+        }
+    }
+}
+
+enum UpdateState {
+    Idle,
+    Receiving { size: u32, crc: u32, written: u32 },
+    Verified { size: u32 },
+}
+
+/// Receives a new firmware image from the host into the slot which isn't running,
+/// then hands it to the bootloader once it has been verified.
+pub struct FirmwareUpdate {
+    flash: FLASH,
+    state: UpdateState,
+}
+
+impl FirmwareUpdate {
+    pub fn new(flash: FLASH) -> Self {
+        Self { flash, state: UpdateState::Idle }
+    }
+
+    /// The slot updates are written to. The image has to be linked for it.
+    pub fn target_slot(&self) -> SlotId {
+        running_slot().other()
+    }
+
+    /// The number of bytes of the image received so far.
+    pub fn written(&self) -> u32 {
+        match self.state {
+            UpdateState::Idle => 0,
+            UpdateState::Receiving { written, .. } => written,
+            UpdateState::Verified { size } => size,
+        }
+    }
+
+    /// Starts receiving an image of `size` bytes, which should have a CRC-32 of `crc`.
+    /// This erases the target slot, which takes a couple of seconds.
+    pub fn begin(
+        &mut self,
+        size: u32,
+        crc: u32,
+        watchdog: &mut Watchdog,
+    ) -> Result<(), UpdateError> {
+        self.state = UpdateState::Idle;
+
+        if size == 0 || size > slots::SLOT_SIZE {
+            return Err(UpdateError::TooLarge);
+        }
+
+        self.unlock();
+        let result = self
+            .target_slot()
+            .sectors()
+            .iter()
+            .try_for_each(|&sector| self.erase_sector(sector, watchdog));
+        self.lock();
+
+        result?;
+        self.state = UpdateState::Receiving { size, crc, written: 0 };
+        Ok(())
+    }
+
+    /// Writes the next chunk of the image. Returns the number of bytes written so far.
+    pub fn write_chunk(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        watchdog: &mut Watchdog,
+    ) -> Result<u32, UpdateError> {
+        let (size, written) = match self.state {
+            UpdateState::Receiving { size, written, .. } => (size, written),
+            _ => return Err(UpdateError::NotStarted),
+        };
+
+        if offset != written || offset % 4 != 0 {
+            return Err(UpdateError::BadOffset);
+        }
+        if offset + data.len() as u32 > size {
+            return Err(UpdateError::TooLarge);
+        }
+
+        let start = self.target_slot().start() + offset;
+
+        self.unlock();
+        let result = data.chunks(4).enumerate().try_for_each(|(i, chunk)| {
+            // The last word of the image is padded with the erased value.
+            let mut word = [0xFF; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+
+            self.program_word(start + i as u32 * 4, u32::from_le_bytes(word), watchdog)
+        });
+        self.lock();
+
+        if let Err(e) = result {
+            self.state = UpdateState::Idle;
+            return Err(e);
+        }
+
+        let written = written + data.len() as u32;
+        if let UpdateState::Receiving { written: ref mut total, .. } = self.state {
+            *total = written;
+        }
+
+        Ok(written)
+    }
+
+    /// Checks the complete image against the CRC given to `begin()`, and its signature
+    /// against the firmware's key.
+    pub fn finish(
+        &mut self,
+        signature: Option<&[u8; SIGNATURE_LEN]>,
+        watchdog: &mut Watchdog,
+    ) -> Result<(), UpdateError> {
+        let (size, crc) = match self.state {
+            UpdateState::Receiving { size, crc, written } if written == size => (size, crc),
+            UpdateState::Receiving { .. } => return Err(UpdateError::BadOffset),
+            _ => return Err(UpdateError::NotStarted),
+        };
+
+        let target = self.target_slot();
+        // Safety: The image was written to the target slot, which it fits in.
+        let image =
+            unsafe { core::slice::from_raw_parts(target.start() as *const u8, size as usize) };
+
+        let mut image_crc = Crc32::new();
+        image_crc.update(image);
+        if image_crc.finish() != crc {
+            self.state = UpdateState::Idle;
+            return Err(UpdateError::CrcMismatch);
+        }
+
+        if size < 8 || !slots::image_is_valid(target) {
+            self.state = UpdateState::Idle;
+            return Err(UpdateError::InvalidImage);
+        }
+
+        if let Err(e) = verify_signature(image, signature, watchdog) {
+            self.state = UpdateState::Idle;
+            return Err(e);
+        }
+
+        self.state = UpdateState::Verified { size };
+        Ok(())
+    }
+
+    /// Abandons the current update, if there is one.
+    pub fn abort(&mut self) {
+        self.state = UpdateState::Idle;
+    }
+
+    /// Resets into the verified image, which the bootloader boots on trial.
+    /// Does nothing if there's no verified image.
+    pub fn install(&mut self, backup_domain: &mut BackupDomain) {
+        if let UpdateState::Verified { .. } = self.state {
+            backup_domain.write(&BOOT_TRIAL, Trial::Pending(self.target_slot()));
+
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+
+    /// Makes the running slot the one the bootloader boots from now on, ending its trial.
+    pub fn confirm_running_slot(
+        &mut self,
+        backup_domain: &mut BackupDomain,
+        watchdog: &mut Watchdog,
+    ) -> Result<(), UpdateError> {
+        let slot = running_slot();
+
+        if slots::read_active_slot() != Some(slot) {
+            self.unlock();
+            let result = self.append_active_record(slot, watchdog);
+            self.lock();
+            result?;
+        }
+
+        backup_domain.write(&BOOT_TRIAL, Trial::None);
+        Ok(())
+    }
+
+    fn append_active_record(
+        &self,
+        slot: SlotId,
+        watchdog: &mut Watchdog,
+    ) -> Result<(), UpdateError> {
+        let address = match slots::free_record_address() {
+            Some(address) => address,
+            None => {
+                self.erase_sector(slots::BOOT_STATE_SECTOR, watchdog)?;
+                slots::BOOT_STATE_START
+            },
+        };
+
+        let [word, check] = slots::active_record(slot);
+        self.program_word(address, word, watchdog)?;
+        self.program_word(address + 4, check, watchdog)
+    }
+
+    fn unlock(&self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            // Safety: Writing the documented key sequence.
+            self.flash.keyr.write(|w| unsafe { w.bits(FLASH_KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(FLASH_KEY2) });
+        }
+    }
+
+    fn lock(&self) {
+        self.flash.cr.write(|w| w.lock().set_bit());
+    }
+
+    fn erase_sector(&self, sector: u32, watchdog: &mut Watchdog) -> Result<(), UpdateError> {
+        self.wait_ready(watchdog)?;
+        self.write_cr(CR_SER | CR_PSIZE_X32 | (sector << CR_SNB_SHIFT));
+
+        watchdog.feed();
+        watchdog.with_timeout_ms(ERASE_WATCHDOG_TIMEOUT_MS, || {
+            self.write_cr(CR_SER | CR_PSIZE_X32 | (sector << CR_SNB_SHIFT) | CR_STRT);
+            while self.is_busy() {}
+            self.take_errors()
+        })
+    }
+
+    fn program_word(
+        &self,
+        address: u32,
+        word: u32,
+        watchdog: &mut Watchdog,
+    ) -> Result<(), UpdateError> {
+        self.wait_ready(watchdog)?;
+        self.write_cr(CR_PG | CR_PSIZE_X32);
+        // Safety: Only called with addresses in the target slot or the boot state sector,
+        // never the running firmware.
+        unsafe { ptr::write_volatile(address as *mut u32, word) };
+        self.wait_ready(watchdog)
+    }
+
+    fn write_cr(&self, bits: u32) {
+        // Safety: Only the documented PG, SER, SNB, PSIZE and STRT bits are used.
+        self.flash.cr.write(|w| unsafe { w.bits(bits) });
+    }
+
+    fn wait_ready(&self, watchdog: &mut Watchdog) -> Result<(), UpdateError> {
+        while self.is_busy() {
+            watchdog.feed();
+        }
+
+        self.take_errors()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.flash.sr.read().bits() & SR_BSY != 0
+    }
+
+    fn take_errors(&self) -> Result<(), UpdateError> {
+        let errors = self.flash.sr.read().bits() & SR_ERRORS;
+        if errors != 0 {
+            // Safety: The error flags are cleared by writing 1 to them.
+            self.flash.sr.write(|w| unsafe { w.bits(errors) });
+            return Err(UpdateError::FlashError);
+        }
+
+        Ok(())
+    }
+}
+
+fn verify_signature(
+    image: &[u8],
+    signature: Option<&[u8; SIGNATURE_LEN]>,
+    watchdog: &mut Watchdog,
+) -> Result<(), UpdateError> {
+    let public_key = PublicKey::new(UPDATE_PUBLIC_KEY.ok_or(UpdateError::NoUpdateKey)?);
+    let signature = Signature::new(*signature.ok_or(UpdateError::Unsigned)?);
+
+    watchdog.feed();
+    watchdog
+        .with_timeout_ms(VERIFY_WATCHDOG_TIMEOUT_MS, || public_key.verify(image, &signature))
+        .map_err(|_| UpdateError::BadSignature)
+}
+
+/// A freshly installed firmware runs on trial until it has heard from the host. If that
+/// doesn't happen in time it stops feeding the watchdog, and the reset rolls back to the
+/// previous firmware.
+pub struct SlotTrial {
+    started: Option<Instant>,
+    expired: bool,
+    rolled_back: bool,
+}
+
+impl SlotTrial {
+    /// Finds out from the bootloader whether this boot is a trial, or a rollback from one.
+    pub fn take(backup_domain: &mut BackupDomain, now: Instant) -> Self {
+        let (started, rolled_back) = match backup_domain.read(&BOOT_TRIAL) {
+            Trial::Booted(slot) if slot == running_slot() => (Some(now), false),
+            Trial::RolledBack(_) => {
+                backup_domain.write(&BOOT_TRIAL, Trial::None);
+                (None, true)
+            },
+            _ => (None, false),
+        };
+
+        Self { started, expired: false, rolled_back }
+    }
+
+    /// True until the firmware has been confirmed.
+    pub fn is_pending(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// True if the previous boot was an update which never confirmed.
+    pub fn rolled_back(&self) -> bool {
+        self.rolled_back
+    }
+
+    pub fn confirmed(&mut self) {
+        self.started = None;
+    }
+
+    /// True once the trial has run out without a confirmation.
+    pub fn has_expired(&mut self, now: Instant) -> bool {
+        if let Some(started) = self.started {
+            // Latched, as the clock wraps around eventually.
+            self.expired |= now.ms_since(started) > TRIAL_TIMEOUT_MS;
+        }
+
+        self.expired
+    }
+}
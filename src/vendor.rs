@@ -0,0 +1,134 @@
+use panel_protocol::ArrayVec;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+};
+
+// A vendor specific USB interface carrying the same byte stream as the serial port, for
+// hosts where something else grabs the tty. It only uses control transfers on endpoint 0,
+// the USB peripheral has no endpoints to spare. Through libusb, with `index` set to the
+// interface number and the vendor request type:
+//
+//   SET_OPEN (OUT, value 1 or 0): the host opens or closes the interface, like DTR.
+//   WRITE (OUT): the data stage holds command bytes, stalled while the panel is busy.
+//   READ (IN): returns up to `length` bytes of reports, an empty reply when there are none.
+const USB_CLASS_VENDOR_SPECIFIC: u8 = 0xFF;
+const VENDOR_SUBCLASS_PANEL: u8 = 0x00;
+const VENDOR_PROTOCOL_PANEL: u8 = 0x00;
+
+const REQUEST_SET_OPEN: u8 = 0x01;
+const REQUEST_WRITE: u8 = 0x02;
+const REQUEST_READ: u8 = 0x03;
+
+// The size of the USB device's control buffer.
+const MAX_TRANSFER_LEN: usize = 128;
+const RECEIVE_BUFFER_LEN: usize = 2 * MAX_TRANSFER_LEN;
+// Reports beyond this are dropped until the host reads some.
+const REPORT_BUFFER_LEN: usize = 512;
+
+pub struct VendorInterface {
+    interface: InterfaceNumber,
+    open: bool,
+    received: ArrayVec<[u8; RECEIVE_BUFFER_LEN]>,
+    reports: ArrayVec<[u8; REPORT_BUFFER_LEN]>,
+}
+
+impl VendorInterface {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        Self {
+            interface: alloc.interface(),
+            open: false,
+            received: ArrayVec::new(),
+            reports: ArrayVec::new(),
+        }
+    }
+
+    /// True while a host program has the interface open.
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Moves the command bytes received so far into `buf`, returning how many there were.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = self.received.len().min(buf.len());
+        buf[..count].copy_from_slice(&self.received[..count]);
+        self.received.drain(..count);
+        count
+    }
+
+    /// Queues a whole report for the host to read, or drops it if there's no room.
+    pub fn write(&mut self, report: &[u8]) {
+        if self.reports.remaining_capacity() >= report.len() {
+            let _ = self.reports.try_extend_from_slice(report);
+        }
+    }
+
+    fn is_for_us(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Vendor
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for VendorInterface {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.interface,
+            USB_CLASS_VENDOR_SPECIFIC,
+            VENDOR_SUBCLASS_PANEL,
+            VENDOR_PROTOCOL_PANEL,
+        )
+    }
+
+    fn reset(&mut self) {
+        self.open = false;
+        self.received.clear();
+        self.reports.clear();
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_for_us(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            REQUEST_SET_OPEN => {
+                self.open = xfer.request().value != 0;
+                // Whatever a previous host program left behind isn't for this one.
+                self.received.clear();
+                self.reports.clear();
+                xfer.accept().ok();
+            },
+            REQUEST_WRITE if self.received.remaining_capacity() >= xfer.data().len() => {
+                let _ = self.received.try_extend_from_slice(xfer.data());
+                xfer.accept().ok();
+            },
+            // Including writes that don't fit, the host tries again after a stall.
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_for_us(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            REQUEST_READ => {
+                let count =
+                    self.reports.len().min(xfer.request().length as usize).min(MAX_TRANSFER_LEN);
+                if xfer.accept_with(&self.reports[..count]).is_ok() {
+                    self.reports.drain(..count);
+                }
+            },
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+}
@@ -0,0 +1,61 @@
+const DEFAULT_MAX_LEVEL: u16 = 100;
+const DEFAULT_STEP: u16 = 1;
+
+/// An absolute volume level owned by the firmware, so it survives the host
+/// daemon restarting.
+pub struct Volume {
+    enabled: bool,
+    level: u16,
+    min: u16,
+    max: u16,
+    step: u16,
+}
+
+impl Volume {
+    pub fn new() -> Self {
+        Self { enabled: false, level: 0, min: 0, max: DEFAULT_MAX_LEVEL, step: DEFAULT_STEP }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn level(&self) -> u16 {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: u16) {
+        self.level = level.clamp(self.min, self.max);
+    }
+
+    /// Sets the range of the level and the amount one detent changes it by.
+    /// Invalid bounds are ignored.
+    pub fn set_bounds(&mut self, min: u16, max: u16, step: u16) {
+        if min > max || step == 0 {
+            return;
+        }
+
+        self.min = min;
+        self.max = max;
+        self.step = step;
+        self.level = self.level.clamp(min, max);
+    }
+
+    /// Applies a (usually accelerated) dial movement to the level.
+    /// Returns the new level if it changed.
+    pub fn apply_diff(&mut self, diff: i8) -> Option<u16> {
+        let delta = diff as i32 * self.step as i32;
+        let level = (self.level as i32 + delta).clamp(self.min as i32, self.max as i32) as u16;
+
+        if level != self.level {
+            self.level = level;
+            Some(level)
+        } else {
+            None
+        }
+    }
+}
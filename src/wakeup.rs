@@ -0,0 +1,85 @@
+use hal::stm32::{self, interrupt, Interrupt, EXTI, NVIC, SYSCFG};
+use stm32f4xx_hal as hal;
+
+// The EXTI lines of the inputs which should wake up the MCU:
+//   PB6, PB7:       the brightness dial encoder.
+//   PA8, PA9:       the volume dial encoder.
+//   PA10:           the dial button.
+// The encoder pins are in alternate function mode, which the HAL's ExtiPin
+// doesn't support, so the lines are configured here directly.
+const INPUT_LINES: u32 = (1 << 6) | (1 << 7) | (1 << 8) | (1 << 9) | (1 << 10);
+
+// SYSCFG_EXTICRx port selection values.
+const PORT_A: u32 = 0b0000;
+const PORT_B: u32 = 0b0001;
+
+/// Makes any edge on the panel's inputs raise an interrupt, so the MCU can sleep
+/// until something happens instead of polling.
+pub fn enable_input_wakeup(syscfg: &SYSCFG, exti: &EXTI) {
+    // Safety: Only the SYSCFG clock enable bit is modified, with read-modify-write.
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+    // Safety: The values written are valid port selections for the EXTI lines above,
+    // and the other lines are left untouched.
+    unsafe {
+        // EXTICR2 maps lines 4-7, 4 bits each.
+        syscfg.exticr2.modify(|r, w| {
+            let mask = (0b1111 << 8) | (0b1111 << 12);
+            w.bits((r.bits() & !mask) | (PORT_B << 8) | (PORT_B << 12))
+        });
+        // EXTICR3 maps lines 8-11.
+        syscfg.exticr3.modify(|r, w| {
+            let mask = 0b1111 | (0b1111 << 4) | (0b1111 << 8);
+            w.bits((r.bits() & !mask) | PORT_A | (PORT_A << 4) | (PORT_A << 8))
+        });
+
+        // Trigger on both edges, so both presses and releases wake us up.
+        exti.rtsr.modify(|r, w| w.bits(r.bits() | INPUT_LINES));
+        exti.ftsr.modify(|r, w| w.bits(r.bits() | INPUT_LINES));
+        exti.pr.write(|w| w.bits(INPUT_LINES));
+        exti.imr.modify(|r, w| w.bits(r.bits() | INPUT_LINES));
+
+        NVIC::unmask(Interrupt::EXTI9_5);
+        NVIC::unmask(Interrupt::EXTI15_10);
+    }
+}
+
+/// Sleeps until an interrupt occurs, such as an input edge, USB traffic or the SysTick,
+/// see `clock`.
+pub fn sleep() {
+    // With interrupts disabled, USB traffic arriving between unmasking its interrupt and
+    // the WFI still wakes us up, the handler just runs afterwards.
+    cortex_m::interrupt::free(|_| {
+        // Safety: The handler only masks the interrupt again.
+        unsafe {
+            NVIC::unmask(Interrupt::OTG_FS);
+        }
+        cortex_m::asm::wfi();
+    });
+}
+
+fn clear_pending_inputs() {
+    // Safety: EXTI_PR is write-1-to-clear, so this only affects our own lines.
+    unsafe {
+        (*EXTI::ptr()).pr.write(|w| w.bits(INPUT_LINES));
+    }
+}
+
+// The interrupts only exist to wake the MCU up, the main loop polls the inputs.
+#[interrupt]
+fn EXTI9_5() {
+    clear_pending_inputs();
+}
+
+#[interrupt]
+fn EXTI15_10() {
+    clear_pending_inputs();
+}
+
+// The USB peripheral is polled by the main loop too. Its interrupt stays raised until the
+// main loop gets to it, so it's masked until the next sleep instead of being cleared here.
+#[interrupt]
+fn OTG_FS() {
+    NVIC::mask(Interrupt::OTG_FS);
+}
@@ -0,0 +1,47 @@
+use stm32f4xx_hal as hal;
+
+use hal::{prelude::*, stm32, watchdog::IndependentWatchdog};
+
+pub const DEFAULT_TIMEOUT_MS: u32 = 1000;
+// Anything shorter risks resets during the blocking LED and USB writes.
+const MIN_TIMEOUT_MS: u32 = 100;
+
+/// The independent watchdog resets the MCU unless it's fed regularly, which gets a hung
+/// panel going again without someone having to power-cycle it.
+pub struct Watchdog {
+    iwdg: IndependentWatchdog,
+    timeout_ms: u32,
+}
+
+impl Watchdog {
+    pub fn start(iwdg: stm32::IWDG, timeout_ms: u32) -> Self {
+        let timeout_ms = timeout_ms.max(MIN_TIMEOUT_MS);
+        let mut iwdg = IndependentWatchdog::new(iwdg);
+        iwdg.start(timeout_ms.ms());
+
+        Self { iwdg, timeout_ms }
+    }
+
+    /// Changes the timeout of the running watchdog.
+    pub fn set_timeout_ms(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms.max(MIN_TIMEOUT_MS);
+        self.iwdg.start(self.timeout_ms.ms());
+    }
+
+    /// Runs `f` with a longer timeout, for the rare long computation which can't stop to
+    /// feed the watchdog. The old timeout is restored afterwards.
+    pub fn with_timeout_ms<R>(&mut self, timeout_ms: u32, f: impl FnOnce() -> R) -> R {
+        let previous_ms = self.timeout_ms;
+
+        self.iwdg.start(timeout_ms.max(previous_ms).ms());
+        let result = f();
+        self.iwdg.start(previous_ms.ms());
+
+        result
+    }
+
+    /// Should only be called from a healthy main loop.
+    pub fn feed(&mut self) {
+        self.iwdg.feed();
+    }
+}